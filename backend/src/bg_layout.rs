@@ -1,13 +1,17 @@
+use futures_util::StreamExt;
 use std::backtrace::Backtrace;
-use std::sync::atomic::AtomicBool;
+use std::collections::HashMap;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 
-use crate::graph::GraphResponse;
+use crate::graph::{Graph, GraphResponse, NodeId, Pos};
 use crate::graph_data::GraphDataType;
-use crate::layout::Layout;
+use crate::graph_delta::{quantise, GraphDelta};
+use crate::layout::{routing, LayoutEngine};
 
 #[derive(thiserror::Error, Debug)]
 #[allow(clippy::enum_variant_names)]
@@ -39,21 +43,87 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub struct BgLayout {
     graph_data: GraphDataType,
     exit_requested: Arc<AtomicBool>,
+    layout: Option<(usize, Box<dyn LayoutEngine>)>, // with the layout serial it was created for
     layout_finished_serial: Option<usize>,
+    last_sent: Option<GraphResponse>,
+    update_serial: Arc<AtomicU64>, // serial of the most recently sent update
 }
 
+/// Updates are numbered with increasing serials. A delta applies on top of any state between
+/// its base serial and its serial, while a snapshot is self-contained.
 #[derive(serde::Serialize, Debug, Clone)]
-pub struct Update {
-    graph: GraphResponse,
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Update {
+    Snapshot {
+        serial: u64,
+        graph: GraphResponse,
+    },
+    Delta {
+        serial: u64,
+        base_serial: u64,
+        delta: GraphDelta,
+    },
+}
+
+impl Update {
+    pub fn serial(&self) -> u64 {
+        match self {
+            Update::Snapshot { serial, .. } => *serial,
+            Update::Delta { serial, .. } => *serial,
+        }
+    }
+
+    /// Combines this update with the one following it
+    fn merge(self, later: Update) -> Update {
+        match (self, later) {
+            (_, later @ Update::Snapshot { .. }) => later,
+            // The snapshot may be newer than deltas that were sent before it was taken
+            (snapshot @ Update::Snapshot { .. }, Update::Delta { serial, .. })
+                if serial <= snapshot.serial() =>
+            {
+                snapshot
+            }
+            (Update::Snapshot { mut graph, .. }, Update::Delta { serial, delta, .. }) => {
+                delta.apply_to(&mut graph);
+                Update::Snapshot { serial, graph }
+            }
+            (
+                Update::Delta {
+                    base_serial,
+                    mut delta,
+                    ..
+                },
+                Update::Delta {
+                    serial,
+                    delta: later_delta,
+                    ..
+                },
+            ) => {
+                delta.merge(later_delta);
+                Update::Delta {
+                    serial,
+                    base_serial,
+                    delta,
+                }
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct UpdateStats {
+    pub subscribers: usize,
+    /// How many times a subscriber has fallen behind and was sent a snapshot instead
+    pub resyncs: u64,
 }
 
-// TODO: This is for future use.. ?
 #[derive(Clone)]
-#[allow(dead_code)]
 pub struct BgControl {
     graph_data: GraphDataType,
     exit_requested: Arc<AtomicBool>,
     updates_tx: broadcast::WeakSender<Update>,
+    update_serial: Arc<AtomicU64>,
+    resyncs: Arc<AtomicU64>,
 }
 
 impl BgControl {
@@ -63,14 +133,71 @@ impl BgControl {
         self.exit_requested.store(true, Relaxed);
     }
 
-    pub fn updates(&self) -> broadcast::Receiver<Update> {
-        // TODO: it would be better to always provide the current state first, so the
-        // client can only subscribe to SSE and get all the data
+    /// Subscribe to the updates. Use snapshot after subscribing to get the initial state.
+    fn updates(&self) -> broadcast::Receiver<Update> {
         match self.updates_tx.upgrade() {
             Some(updates_tx) => updates_tx.subscribe(),
             None => todo!(),
         }
     }
+
+    /// The current state of the graph, for clients that aren't in sync with the updates.
+    /// The deltas following it may partially predate it, but as they only contain the new
+    /// values of what changed, applying them leads to the correct state.
+    pub async fn snapshot(&self) -> Update {
+        let data = self.graph_data.lock().await;
+        let mut graph = data.graph.graph_response();
+        quantise(&mut graph);
+        Update::Snapshot {
+            serial: self.update_serial.load(Relaxed),
+            graph,
+        }
+    }
+
+    /// The stream of updates for a single client, starting with a snapshot. When the client
+    /// is slow to consume the stream, the updates waiting for it are merged into one.
+    pub async fn subscribe(&self) -> impl futures_util::Stream<Item = Update> {
+        // Subscribing before taking the snapshot ensures no changes fall between the two
+        let updates = self.updates();
+        let snapshot = self.snapshot().await;
+        let bg_control = self.clone();
+        let updates = futures_util::stream::unfold(updates, move |mut updates| {
+            let bg_control = bg_control.clone();
+            async move {
+                let mut update = match updates.recv().await {
+                    Ok(update) => update,
+                    Err(RecvError::Lagged(_)) => bg_control.resync().await,
+                    Err(RecvError::Closed) => return None,
+                };
+                loop {
+                    update = match updates.try_recv() {
+                        Ok(next) => update.merge(next),
+                        Err(TryRecvError::Lagged(_)) => bg_control.resync().await,
+                        Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+                    };
+                }
+                Some((update, updates))
+            }
+        });
+        futures_util::stream::once(std::future::ready(snapshot)).chain(updates)
+    }
+
+    // The subscriber missed some updates, so it needs the whole graph
+    async fn resync(&self) -> Update {
+        self.resyncs.fetch_add(1, Relaxed);
+        self.snapshot().await
+    }
+
+    pub fn stats(&self) -> UpdateStats {
+        UpdateStats {
+            subscribers: self
+                .updates_tx
+                .upgrade()
+                .map(|updates_tx| updates_tx.receiver_count())
+                .unwrap_or(0),
+            resyncs: self.resyncs.load(Relaxed),
+        }
+    }
 }
 
 impl BgLayout {
@@ -79,50 +206,132 @@ impl BgLayout {
         BgLayout {
             graph_data,
             exit_requested,
+            layout: None,
             layout_finished_serial: None,
+            last_sent: None,
+            update_serial: Arc::new(AtomicU64::new(0)),
         }
     }
 
     pub fn start(self: BgLayout) -> BgControl {
         let exit_requested = self.exit_requested.clone();
         let graph_data = self.graph_data.clone();
+        let update_serial = self.update_serial.clone();
         let (updates_tx, _updates_rx) = broadcast::channel(10);
         let _join = tokio::spawn(self.run(updates_tx.clone()));
         BgControl {
             graph_data,
             exit_requested,
             updates_tx: updates_tx.downgrade(),
+            update_serial,
+            resyncs: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// Runs a step of the layout. The layout is computed on a blocking thread from a copy of
+    /// the graph, so the graph data is only locked while the positions are read and written.
+    #[allow(clippy::result_large_err)]
     async fn do_layout(self: &mut BgLayout) -> Result<bool, Error> {
-        let mut data = self.graph_data.lock().await;
-        if data.is_empty() {
-            self.layout_finished_serial = Some(data.graph.get_change_serial());
-            Ok(true)
-        } else {
-            if Some(data.graph.get_change_serial()) != self.layout_finished_serial {
-                let layout = data.update_layout()?;
-                let (nodes_edges, is_finished) = layout.step();
-                Layout::apply(&nodes_edges, &mut data.graph)?;
+        let (request, start_positions) = {
+            let data = self.graph_data.lock().await;
+            if data.is_empty() {
+                // When the last node gets removed, report one unfinished round so that the
+                // clients get informed about the now-empty graph
+                let serial = Some(data.graph.get_change_serial());
+                let is_finished = self.layout_finished_serial == serial;
+                self.layout_finished_serial = serial;
+                return Ok(is_finished);
+            }
+            if data.is_settled() {
+                // A restored graph keeps its saved positions until it changes
+                return Ok(true);
+            }
+            // A reset layout is run again even if the graph hasn't changed, e.g. when the
+            // engine has been switched
+            let is_current =
+                matches!(&self.layout, Some((serial, _)) if *serial == data.layout_serial());
+            if is_current && Some(data.graph.get_change_serial()) == self.layout_finished_serial {
+                return Ok(true);
+            }
+            (
+                (!is_current).then(|| data.layout_request()),
+                node_positions(&data.graph),
+            )
+        };
+
+        let layout = self.layout.take();
+        let ((layout_serial, layout), mut nodes_edges, is_finished) =
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let (layout_serial, mut layout) = match request {
+                    Some(request) => (request.layout_serial, request.new_engine()?),
+                    None => layout.expect("Expected a current layout"),
+                };
+                let (mut nodes_edges, is_finished) = layout.step();
                 if is_finished {
-                    self.layout_finished_serial = Some(data.graph.get_change_serial());
+                    // The edges are routed once the nodes have stopped moving
+                    routing::route_edges(&mut nodes_edges);
                 }
-                Ok(is_finished)
-            } else {
-                Ok(true)
-            }
+                Ok(((layout_serial, layout), nodes_edges, is_finished))
+            })
+            .await
+            .expect("Expected layout thread to succeed")?;
+
+        let mut data = self.graph_data.lock().await;
+        let is_stale = layout_serial != data.layout_serial();
+        if is_stale {
+            // The graph changed while the step was being computed. The nodes that haven't
+            // been moved meanwhile still get their positions from the step, so that the
+            // layout makes progress while the graph keeps changing, but the nodes that have
+            // keep their new positions. The routes are left for the new layout.
+            let current = node_positions(&data.graph);
+            nodes_edges
+                .nodes
+                .retain(|node| current.get(&node.id) == start_positions.get(&node.id));
+            nodes_edges.edges.clear();
+        }
+        layout.apply(&nodes_edges, &mut data.graph)?;
+        data.layout_applied(layout_serial);
+        if is_stale {
+            // The layout starts again from the new graph
+            return Ok(false);
+        }
+        if is_finished {
+            self.layout_finished_serial = Some(data.graph.get_change_serial());
         }
+        self.layout = Some((layout_serial, layout));
+        Ok(is_finished)
     }
 
     async fn send_update(
-        self: &BgLayout,
+        self: &mut BgLayout,
         updates_tx: &broadcast::Sender<Update>,
     ) -> Result<(), tokio::sync::broadcast::error::SendError<Update>> {
-        let data = self.graph_data.lock().await;
-        let update = Update {
-            graph: data.graph.graph_response(),
+        // The comparison with the previous update is done without holding the lock
+        let mut graph = self.graph_data.lock().await.graph.graph_response();
+        quantise(&mut graph);
+        let base_serial = self.update_serial.load(Relaxed);
+        let serial = base_serial + 1;
+        let update = match &self.last_sent {
+            Some(last_sent) => {
+                let delta = GraphDelta::between(last_sent, &graph);
+                if delta.is_empty() {
+                    return Ok(());
+                }
+                Update::Delta {
+                    serial,
+                    base_serial,
+                    delta,
+                }
+            }
+            None => Update::Snapshot {
+                serial,
+                graph: graph.clone(),
+            },
         };
+        // The update counts as sent even without subscribers, as the new subscribers start
+        // from a snapshot anyway
+        self.last_sent = Some(graph);
+        self.update_serial.store(serial, Relaxed);
         let _subscriber_count = updates_tx.send(update)?;
         Ok(())
     }
@@ -141,3 +350,12 @@ impl BgLayout {
         }
     }
 }
+
+// The positions of the nodes, for telling which ones have been moved during a step
+fn node_positions(graph: &Graph) -> HashMap<NodeId, Option<Pos>> {
+    graph
+        .graph
+        .node_weights()
+        .map(|node| (node.id.clone(), node.pos.clone()))
+        .collect()
+}
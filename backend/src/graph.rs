@@ -1,10 +1,11 @@
 use bimap::BiMap;
 use petgraph::graph::{EdgeIndex, NodeIndex};
 use petgraph::visit::EdgeRef;
+use petgraph::Direction::{Incoming, Outgoing};
 use petgraph::Graph as PetGraph;
 use serde::{Deserialize, Serialize};
 use std::backtrace::Backtrace;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr as _;
 use std::time::SystemTime;
 
@@ -23,9 +24,6 @@ pub enum Error {
     #[error("Internal error: edge index {index} not found")]
     EdgeIndexNotFound { index: usize, backtrace: Backtrace },
 
-    #[error("Unsupported edge node type")]
-    UnsupportedEdgeNode,
-
     #[error(transparent)]
     GraphvizParseError(#[from] anyhow::Error),
 }
@@ -62,7 +60,7 @@ impl Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Ord, Hash)]
 pub struct NodeId(String);
 
 impl Eq for NodeId {}
@@ -79,7 +77,7 @@ impl From<NodeId> for String {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Ord, Hash)]
 pub struct EdgeId(String);
 
 impl Eq for EdgeId {}
@@ -96,39 +94,145 @@ impl From<EdgeId> for String {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Graphviz attributes, such as color or shape, kept as-is
+pub type Attributes = BTreeMap<String, String>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NodeData {
     pub label: String,
+    #[serde(default)]
+    pub attrs: Attributes,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl NodeData {
+    /// Merges Graphviz attributes to the node. The label attribute goes to its own field.
+    fn merge_attrs(&mut self, node_id: &NodeId, mut attrs: Attributes) {
+        if let Some(label) = attrs.remove("label") {
+            // \N stands for the node name in Graphviz
+            self.label = label.replace("\\N", &node_id.0);
+        }
+        self.attrs.extend(attrs);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Pos(pub f64, pub f64);
 
+impl Pos {
+    /// Parses a Graphviz position "x,y", which is followed by ! when the node is fixed there
+    fn parse_graphviz(value: &str) -> Option<(Pos, bool)> {
+        let value = value.trim();
+        let (value, fixed) = match value.strip_suffix('!') {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+        let mut coords = value.split(',').map(|coord| coord.trim().parse::<f64>());
+        match (coords.next(), coords.next()) {
+            (Some(Ok(x)), Some(Ok(y))) => Some((Pos(x, y), fixed)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Node {
     pub id: NodeId,
     pub data: NodeData,
     pub pos: Option<Pos>,
+    /// Pinned nodes stay where they are put, and the layout only moves the other nodes
+    #[serde(default)]
+    pub pinned: bool,
 }
 
 impl Node {
     pub fn layout_node(&self) -> fjadra::Node {
         let node = fjadra::Node::default();
-        if let Some(Pos(x, y)) = &self.pos {
-            node.position(*x, *y)
-        } else {
-            node
+        match &self.pos {
+            Some(Pos(x, y)) if self.pinned => node.position(*x, *y).fixed_position(*x, *y),
+            Some(Pos(x, y)) => node.position(*x, *y),
+            None => node,
         }
     }
 
     pub fn set_pos(&mut self, pos: Pos) {
         self.pos = Some(pos);
     }
+
+    /// Merges Graphviz attributes to the node. A position, pos="x,y", is where the layout
+    /// starts from, unless the node already has a position. A fixed position, pos="x,y!",
+    /// pins the node.
+    fn merge_attrs(&mut self, mut attrs: Attributes) {
+        if let Some((pos, fixed)) = attrs.get("pos").and_then(|pos| Pos::parse_graphviz(pos)) {
+            attrs.remove("pos");
+            if fixed {
+                self.pos = Some(pos);
+                self.pinned = true;
+            } else if self.pos.is_none() {
+                self.pos = Some(pos);
+            }
+        }
+        self.data.merge_attrs(&self.id, attrs);
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Edge {
     pub id: EdgeId,
+    #[serde(default)]
+    pub attrs: Attributes,
+    /// The control points of the cubic Bézier curves drawn for the edge, from the source
+    /// node to the target node, or empty for a straight line
+    #[serde(default)]
+    pub route: Vec<Pos>,
+}
+
+impl Edge {
+    /// How strongly the edge pulls its ends together, from the weight attribute
+    pub fn weight(&self) -> f64 {
+        self.number_attr("weight", 1.0).max(0.0)
+    }
+
+    /// The preferred length of the edge relative to the others, from the len attribute
+    pub fn length(&self) -> f64 {
+        self.number_attr("len", 1.0).max(0.0)
+    }
+
+    /// The minimum number of layers the edge spans, from the minlen attribute
+    pub fn minlen(&self) -> f64 {
+        self.number_attr("minlen", 1.0).max(0.0)
+    }
+
+    fn number_attr(&self, name: &str, default: f64) -> f64 {
+        self.attrs
+            .get(name)
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .filter(|value| value.is_finite())
+            .unwrap_or(default)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GroupId(String);
+
+/// A named subgraph. Clusters, the subgraphs with names starting with "cluster", are also
+/// kept together by the layout.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Group {
+    pub id: GroupId,
+    pub label: Option<String>,
+    pub parent: Option<GroupId>,
+    pub nodes: Vec<NodeId>, // only the nodes directly in this group
+    pub cluster: bool,
+    pub attrs: Attributes,
+}
+
+impl Group {
+    fn merge_attrs(&mut self, mut attrs: Attributes) {
+        if let Some(label) = attrs.remove("label") {
+            self.label = Some(label);
+        }
+        self.attrs.extend(attrs);
+    }
 }
 
 pub type PetGraphType = PetGraph<Node, Edge>;
@@ -141,6 +245,9 @@ pub struct Graph {
     id_counter: usize,
     creation_time: SystemTime,
     change_serial: usize, // increase on every addition/removal of node/edge
+    directed: bool,
+    attrs: Attributes, // graph attributes, such as rankdir
+    groups: BTreeMap<GroupId, Group>,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
@@ -148,6 +255,21 @@ pub struct GraphResponse {
     pub nodes: Vec<Node>,
     pub edges: Vec<(NodeId, NodeId, Edge)>,
     pub creation_time: f64,
+    pub directed: bool,
+    pub attrs: Attributes,
+    pub groups: Vec<Group>,
+}
+
+/// The whole graph in the form it's saved in, including the nodes without positions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GraphSnapshot {
+    nodes: Vec<Node>,
+    edges: Vec<(NodeId, NodeId, Edge)>,
+    id_counter: usize,
+    creation_time: SystemTime,
+    directed: bool,
+    attrs: Attributes,
+    groups: Vec<Group>,
 }
 
 impl Graph {
@@ -159,6 +281,9 @@ impl Graph {
             id_counter: 0usize,
             creation_time: SystemTime::now(),
             change_serial: 0usize,
+            directed: true,
+            attrs: Attributes::new(),
+            groups: BTreeMap::new(),
         }
     }
 
@@ -166,6 +291,10 @@ impl Graph {
         self.change_serial
     }
 
+    pub fn attrs(&self) -> &Attributes {
+        &self.attrs
+    }
+
     pub fn graph_response(&self) -> GraphResponse {
         // Graph response filters out nodes without position. This simplifies the client side a bit.
         let filter_unpositioned = true;
@@ -208,7 +337,59 @@ impl Graph {
             nodes,
             edges,
             creation_time,
+            directed: self.directed,
+            attrs: self.attrs.clone(),
+            groups: self.groups.values().cloned().collect(),
+        }
+    }
+
+    /// Takes everything needed for restoring the graph later with from_snapshot
+    pub fn snapshot(&self) -> Result<GraphSnapshot> {
+        let edges = self
+            .graph
+            .edge_references()
+            .map(|edge| {
+                Ok((
+                    self.resolve_node_id(edge.source())?,
+                    self.resolve_node_id(edge.target())?,
+                    edge.weight().clone(),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(GraphSnapshot {
+            nodes: self.graph.node_weights().cloned().collect(),
+            edges,
+            id_counter: self.id_counter,
+            creation_time: self.creation_time,
+            directed: self.directed,
+            attrs: self.attrs.clone(),
+            groups: self.groups.values().cloned().collect(),
+        })
+    }
+
+    /// Restores a graph from a snapshot, with the positions of the nodes and the routes of
+    /// the edges
+    pub fn from_snapshot(snapshot: GraphSnapshot) -> Result<Graph> {
+        let mut graph = Graph {
+            id_counter: snapshot.id_counter,
+            creation_time: snapshot.creation_time,
+            directed: snapshot.directed,
+            attrs: snapshot.attrs,
+            groups: snapshot
+                .groups
+                .into_iter()
+                .map(|group| (group.id.clone(), group))
+                .collect(),
+            ..Graph::new()
+        };
+        for node in snapshot.nodes {
+            graph.add_node(node);
         }
+        for (a, b, edge) in snapshot.edges {
+            graph.add_edge(a, b, Some(edge.id.clone()), edge.attrs)?;
+            graph.get_edge_mut(&edge.id)?.route = edge.route;
+        }
+        Ok(graph)
     }
 
     // Note! This function does not update node_id_map, you need to do it yourself
@@ -240,6 +421,16 @@ impl Graph {
         self.change_serial += 1;
         let node_id = node.id.clone();
         let node_index = if let Some(node_index) = self.node_id_map.get_by_left(&node_id) {
+            // Existing nodes keep their position, but get the new data. Nodes can be pinned,
+            // but not unpinned, this way.
+            let existing = &mut self.graph[*node_index];
+            existing.data = node.data;
+            if node.pinned {
+                existing.pinned = true;
+                if node.pos.is_some() {
+                    existing.pos = node.pos;
+                }
+            }
             *node_index
         } else {
             self.graph.add_node(node)
@@ -255,13 +446,28 @@ impl Graph {
                 id: node_id.clone(),
                 data: NodeData {
                     label: node_id.0.clone(),
+                    attrs: Attributes::new(),
                 },
                 pos: None,
+                pinned: false,
             };
             self.add_node(node);
         }
     }
 
+    /// Moves a node, e.g. when it's dragged by a user
+    pub fn move_node(&mut self, node_id: &NodeId, pos: Pos) -> Result<()> {
+        self.change_serial += 1;
+        self.get_node_mut(node_id)?.set_pos(pos);
+        Ok(())
+    }
+
+    pub fn set_pinned(&mut self, node_id: &NodeId, pinned: bool) -> Result<()> {
+        self.change_serial += 1;
+        self.get_node_mut(node_id)?.pinned = pinned;
+        Ok(())
+    }
+
     pub fn get_node_mut(&mut self, node_id: &NodeId) -> Result<&mut Node> {
         let node_index = self.resolve_node_index(node_id)?;
         self.graph
@@ -269,6 +475,13 @@ impl Graph {
             .ok_or(Error::node_not_found(&node_id.0))
     }
 
+    pub fn get_edge_mut(&mut self, edge_id: &EdgeId) -> Result<&mut Edge> {
+        let edge_index = self.resolve_edge_index(edge_id)?;
+        self.graph
+            .edge_weight_mut(edge_index)
+            .ok_or(Error::edge_not_found(&edge_id.0))
+    }
+
     pub fn node_neighbors(&self, node_id: &NodeId) -> Result<Vec<&Node>> {
         let node_index = self.resolve_node_index(node_id)?;
         self.graph
@@ -296,11 +509,10 @@ impl Graph {
             .clone())
     }
 
-    #[allow(dead_code)]
-    pub fn resolve_edge_index(&self, edge_id: EdgeId) -> Result<EdgeIndex> {
+    pub fn resolve_edge_index(&self, edge_id: &EdgeId) -> Result<EdgeIndex> {
         Ok(*self
             .edge_id_map
-            .get_by_left(&edge_id)
+            .get_by_left(edge_id)
             .ok_or(Error::edge_not_found(&edge_id.0))?)
     }
 
@@ -313,76 +525,628 @@ impl Graph {
             .clone())
     }
 
-    pub fn add_edge(&mut self, a: NodeId, b: NodeId, edge_id: Option<EdgeId>) -> Result<()> {
+    /// Adds an edge between the nodes, replacing the edge with the same id if there is one
+    pub fn add_edge(
+        &mut self,
+        a: NodeId,
+        b: NodeId,
+        edge_id: Option<EdgeId>,
+        attrs: Attributes,
+    ) -> Result<()> {
+        let a_index = self.resolve_node_index(&a)?;
+        let b_index = self.resolve_node_index(&b)?;
         self.change_serial += 1;
         let edge_id = edge_id.unwrap_or_else(|| self.new_edge_id());
+        if self.edge_id_map.contains_left(&edge_id) {
+            // An edge posted again with the same id replaces the old one
+            self.remove_edge(&edge_id)?;
+        }
         let edge = Edge {
             id: edge_id.clone(),
+            attrs,
+            route: Vec::new(),
         };
 
-        let edge_index = self.graph.add_edge(
-            self.resolve_node_index(&a)?,
-            self.resolve_node_index(&b)?,
-            edge,
-        );
+        let edge_index = self.graph.add_edge(a_index, b_index, edge);
         self.edge_id_map.insert(edge_id, edge_index);
         Ok(())
     }
 
+    pub fn remove_edge(&mut self, edge_id: &EdgeId) -> Result<Edge> {
+        let edge_index = self.resolve_edge_index(edge_id)?;
+        self.remove_edge_index(edge_index)
+    }
+
+    // petgraph moves the last edge to the index of the removed edge, so the map entry of
+    // the moved edge is updated accordingly
+    fn remove_edge_index(&mut self, edge_index: EdgeIndex) -> Result<Edge> {
+        self.change_serial += 1;
+        let last_index = EdgeIndex::new(self.graph.edge_count().saturating_sub(1));
+        let edge = self
+            .graph
+            .remove_edge(edge_index)
+            .ok_or(Error::edge_index_not_found(edge_index.index()))?;
+        self.edge_id_map.remove_by_right(&edge_index);
+        if last_index != edge_index {
+            if let Some((moved_id, _)) = self.edge_id_map.remove_by_right(&last_index) {
+                self.edge_id_map.insert(moved_id, edge_index);
+            }
+        }
+        Ok(edge)
+    }
+
+    pub fn remove_node(&mut self, node_id: &NodeId) -> Result<Node> {
+        let node_index = self.resolve_node_index(node_id)?;
+
+        // petgraph would remove the edges by itself, but then we'd lose track of which
+        // edge indices got moved around
+        while let Some(edge_index) = self
+            .graph
+            .first_edge(node_index, Outgoing)
+            .or_else(|| self.graph.first_edge(node_index, Incoming))
+        {
+            self.remove_edge_index(edge_index)?;
+        }
+
+        self.change_serial += 1;
+        let last_index = NodeIndex::new(self.graph.node_count().saturating_sub(1));
+        let node = self
+            .graph
+            .remove_node(node_index)
+            .ok_or(Error::node_index_not_found(node_index.index()))?;
+        self.node_id_map.remove_by_right(&node_index);
+        if last_index != node_index {
+            if let Some((moved_id, _)) = self.node_id_map.remove_by_right(&last_index) {
+                self.node_id_map.insert(moved_id, node_index);
+            }
+        }
+        for group in self.groups.values_mut() {
+            group.nodes.retain(|member_id| *member_id != node.id);
+        }
+        Ok(node)
+    }
+
+    /// Makes this graph match `other`: nodes and edges missing from `other` are removed
+    /// and the new ones are added. Nodes existing in both graphs keep their positions.
+    pub fn replace_with(&mut self, other: Graph) -> Result<()> {
+        self.directed = other.directed;
+        self.attrs = other.attrs.clone();
+        self.groups = other.groups.clone();
+
+        let removed_nodes: Vec<NodeId> = self
+            .node_id_map
+            .left_values()
+            .filter(|node_id| !other.node_id_map.contains_left(node_id))
+            .cloned()
+            .collect();
+        for node_id in removed_nodes {
+            self.remove_node(&node_id)?;
+        }
+
+        // Existing nodes keep their positions, unless pinned to a new one
+        for node in other.graph.node_weights() {
+            self.add_node(node.clone());
+        }
+
+        // Edges generated from DOT don't have stable ids, so they are matched by their
+        // endpoints instead
+        let mut existing_edges: HashMap<(NodeId, NodeId), Vec<EdgeId>> = HashMap::new();
+        for edge in self.graph.edge_references() {
+            existing_edges
+                .entry(self.edge_key(
+                    self.resolve_node_id(edge.source())?,
+                    self.resolve_node_id(edge.target())?,
+                ))
+                .or_default()
+                .push(edge.weight().id.clone());
+        }
+        for edge in other.graph.edge_references() {
+            let key = self.edge_key(
+                other.resolve_node_id(edge.source())?,
+                other.resolve_node_id(edge.target())?,
+            );
+            let attrs = edge.weight().attrs.clone();
+            match existing_edges
+                .get_mut(&key)
+                .and_then(|edge_ids| edge_ids.pop())
+            {
+                Some(edge_id) => self.get_edge_mut(&edge_id)?.attrs = attrs,
+                None => {
+                    let (a, b) = key;
+                    self.add_edge(a, b, None, attrs)?;
+                }
+            }
+        }
+        for edge_id in existing_edges.into_values().flatten() {
+            self.remove_edge(&edge_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// A copy of the graph with the nodes in the order of their ids, and the edges in the
+    /// order of their endpoints and ids. Layouts of the copy don't depend on the order in
+    /// which the nodes and edges were added or removed.
+    pub fn sorted(&self) -> Graph {
+        let mut sorted = Graph {
+            graph: PetGraph::with_capacity(self.graph.node_count(), self.graph.edge_count()),
+            node_id_map: BiMap::new(),
+            edge_id_map: BiMap::new(),
+            id_counter: self.id_counter,
+            creation_time: self.creation_time,
+            change_serial: self.change_serial,
+            directed: self.directed,
+            attrs: self.attrs.clone(),
+            groups: self.groups.clone(),
+        };
+
+        let mut nodes: Vec<&Node> = self.graph.node_weights().collect();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        for node in nodes {
+            let node_index = sorted.graph.add_node(node.clone());
+            sorted.node_id_map.insert(node.id.clone(), node_index);
+        }
+
+        let mut edges: Vec<(&NodeId, &NodeId, &Edge)> = self
+            .graph
+            .edge_references()
+            .map(|edge| {
+                (
+                    &self.graph[edge.source()].id,
+                    &self.graph[edge.target()].id,
+                    edge.weight(),
+                )
+            })
+            .collect();
+        edges.sort_by(|a, b| (a.0, a.1, &a.2.id).cmp(&(b.0, b.1, &b.2.id)));
+        for (a, b, edge) in edges {
+            let edge_index = sorted.graph.add_edge(
+                sorted
+                    .node_id_map
+                    .get_by_left(a)
+                    .copied()
+                    .expect("Missing node index"),
+                sorted
+                    .node_id_map
+                    .get_by_left(b)
+                    .copied()
+                    .expect("Missing node index"),
+                edge.clone(),
+            );
+            sorted.edge_id_map.insert(edge.id.clone(), edge_index);
+        }
+        sorted
+    }
+
+    fn ensure_group(&mut self, group_id: &GroupId, parent: Option<&GroupId>) {
+        // A group keeps the parent it was first defined in, which also prevents cycles
+        self.groups
+            .entry(group_id.clone())
+            .or_insert_with(|| Group {
+                id: group_id.clone(),
+                label: None,
+                parent: parent.cloned(),
+                nodes: Vec::new(),
+                cluster: group_id.0.starts_with("cluster"),
+                attrs: Attributes::new(),
+            });
+    }
+
+    fn is_group_within(&self, group_id: &GroupId, ancestor_id: &GroupId) -> bool {
+        let mut current = Some(group_id);
+        while let Some(group_id) = current {
+            if group_id == ancestor_id {
+                return true;
+            }
+            current = self
+                .groups
+                .get(group_id)
+                .and_then(|group| group.parent.as_ref());
+        }
+        false
+    }
+
+    /// Returns the nodes of each cluster, including the nodes of the groups nested in it
+    pub fn clusters(&self) -> Vec<Vec<NodeIndex>> {
+        self.groups
+            .values()
+            .filter(|cluster| cluster.cluster)
+            .map(|cluster| {
+                let mut members: Vec<NodeIndex> = self
+                    .groups
+                    .values()
+                    .filter(|group| self.is_group_within(&group.id, &cluster.id))
+                    .flat_map(|group| group.nodes.iter())
+                    .filter_map(|node_id| self.node_id_map.get_by_left(node_id).copied())
+                    .collect();
+                members.sort();
+                members.dedup();
+                members
+            })
+            .collect()
+    }
+
+    // In undirected graphs a -- b and b -- a are the same edge
+    fn edge_key(&self, a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+        if self.directed || a <= b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    /// Like parse_graphviz, but replaces the contents of the graph with the given DOT
+    pub fn replace_graphviz(&mut self, data: &str) -> Result<(), Error> {
+        let mut other = Graph::new();
+        other.parse_graphviz(data)?;
+        self.replace_with(other)
+    }
+
     pub fn parse_graphviz(&mut self, data: &str) -> Result<(), Error> {
         let ast = graphviz_parser::DotGraph::from_str(data)?;
-        if let graphviz_parser::DotGraph::Directed(graph) = ast {
-            use graphviz_parser::ast_nodes::Statement;
-            use graphviz_parser::ast_nodes::{EdgeLHS, EdgeRHS};
-            for statement in graph.statements {
-                match statement {
-                    Statement::Node(n) => {
-                        let attrs = attr_map(&n.attribute_list);
-                        let node = Node {
-                            id: NodeId(n.id.clone()),
-                            data: NodeData {
-                                label: attrs.get("label").unwrap_or(&&n.id).to_string(),
-                            },
-                            pos: None,
-                        };
-                        self.add_node(node);
-                    }
-                    Statement::Edge(e) => {
-                        let edge_id = self.new_edge_id();
-                        let lhs_id = match e.lhs {
-                            EdgeLHS::Node(node) => NodeId(node.id),
-                            _ => return Err(Error::UnsupportedEdgeNode),
-                        };
-                        let rhs_id = match *e.rhs {
-                            EdgeRHS::Node(node) => NodeId(node.id),
-                            _ => return Err(Error::UnsupportedEdgeNode),
-                        };
-                        self.ensure_node(&lhs_id);
-                        self.ensure_node(&rhs_id);
-                        self.add_edge(lhs_id, rhs_id, Some(edge_id)).unwrap();
-                    }
-                    _ => {
-                        // Ignore others
+        // The type of the most recently received graph determines the type of the whole graph
+        let graph = match ast {
+            graphviz_parser::DotGraph::Directed(graph) => {
+                self.directed = true;
+                graph
+            }
+            graphviz_parser::DotGraph::Undirected(graph) => {
+                self.directed = false;
+                graph
+            }
+        };
+        let mut graph_attrs = Attributes::new();
+        self.add_dot_statements(graph.statements, &mut DotScope::default(), &mut graph_attrs)?;
+        self.attrs.extend(graph_attrs);
+
+        Ok(())
+    }
+
+    /// Adds the nodes and edges of the statements to the graph. Returns the ids of the nodes
+    /// the statements refer to, for the purposes of using subgraphs as edge endpoints.
+    fn add_dot_statements(
+        &mut self,
+        statements: Vec<graphviz_parser::ast_nodes::Statement>,
+        scope: &mut DotScope,
+        graph_attrs: &mut Attributes,
+    ) -> Result<Vec<NodeId>> {
+        use graphviz_parser::ast_nodes::{AttributeType, Statement};
+        let mut node_ids = Vec::new();
+        for statement in statements {
+            match statement {
+                Statement::Node(n) => {
+                    // Repeated node statements accumulate attributes, like in Graphviz
+                    let node_id = NodeId(n.id);
+                    self.ensure_dot_node(&node_id, scope)?;
+                    self.get_node_mut(&node_id)?
+                        .merge_attrs(attributes(&n.attribute_list));
+                    node_ids.push(node_id);
+                }
+                Statement::Edge(e) => {
+                    node_ids.extend(self.add_dot_edge(e, scope)?);
+                }
+                Statement::Subgraph(subgraph) => {
+                    node_ids.extend(self.add_dot_subgraph(subgraph, scope)?);
+                }
+                Statement::Attribute(a) => {
+                    let attrs = attributes(&Some(a.attribute_list));
+                    match a.attribute_type {
+                        AttributeType::Graph => graph_attrs.extend(attrs),
+                        AttributeType::Node => scope.node.extend(attrs),
+                        AttributeType::Edge => scope.edge.extend(attrs),
                     }
                 }
+                Statement::Assignment(a) => {
+                    graph_attrs.insert(a.lhs, a.rhs);
+                }
             }
-            //assert_eq!(node_ids, vec!["a", "b", "c"]);
         }
+        Ok(node_ids)
+    }
+
+    /// Subgraphs inherit the scope of the enclosing graph, but their own default attribute
+    /// statements don't leak out of them. Named subgraphs become groups.
+    fn add_dot_subgraph(
+        &mut self,
+        subgraph: graphviz_parser::ast_nodes::Subgraph,
+        scope: &DotScope,
+    ) -> Result<Vec<NodeId>> {
+        let mut scope = scope.clone();
+        let mut graph_attrs = Attributes::new();
+        if let Some(id) = subgraph.id {
+            let group_id = GroupId(id);
+            self.ensure_group(&group_id, scope.group.as_ref());
+            scope.group = Some(group_id);
+        }
+        let node_ids =
+            self.add_dot_statements(subgraph.statements, &mut scope, &mut graph_attrs)?;
+        // Graph attributes of anonymous subgraphs are not kept
+        if let Some(group) = scope
+            .group
+            .and_then(|group_id| self.groups.get_mut(&group_id))
+        {
+            group.merge_attrs(graph_attrs);
+        }
+        Ok(node_ids)
+    }
 
+    // Newly created nodes get the default node attributes, and all mentioned nodes become
+    // members of the group being defined
+    fn ensure_dot_node(&mut self, node_id: &NodeId, scope: &DotScope) -> Result<()> {
+        if !self.node_id_map.contains_left(node_id) {
+            self.ensure_node(node_id);
+            self.get_node_mut(node_id)?.merge_attrs(scope.node.clone());
+        }
+        if let Some(group) = scope
+            .group
+            .as_ref()
+            .and_then(|group_id| self.groups.get_mut(group_id))
+        {
+            if !group.nodes.contains(node_id) {
+                group.nodes.push(node_id.clone());
+            }
+        }
         Ok(())
     }
+
+    /// Adds the edges of an edge statement, which may be a chain such as a -> b -> c. A
+    /// subgraph as an endpoint stands for all the nodes in it, so {a b} -> c adds two edges.
+    fn add_dot_edge(
+        &mut self,
+        edge: graphviz_parser::ast_nodes::EdgeStatement,
+        scope: &DotScope,
+    ) -> Result<Vec<NodeId>> {
+        use graphviz_parser::ast_nodes::EdgeRHS;
+        // The attribute lists in the chain apply to all of its edges, so the whole chain is
+        // walked before the edges are added
+        let mut attrs = scope.edge.clone();
+        attrs.extend(attributes(&edge.attribute_list));
+        let mut chain = vec![self.add_dot_edge_lhs(edge.lhs, scope)?];
+        let mut rhs = *edge.rhs;
+        loop {
+            match rhs {
+                EdgeRHS::Node(node) => {
+                    let node_id = NodeId(node.id);
+                    self.ensure_dot_node(&node_id, scope)?;
+                    chain.push(vec![node_id]);
+                    break;
+                }
+                EdgeRHS::Subgraph(subgraph) => {
+                    chain.push(self.add_dot_subgraph(subgraph, scope)?);
+                    break;
+                }
+                EdgeRHS::Edge(chained) => {
+                    attrs.extend(attributes(&chained.attribute_list));
+                    chain.push(self.add_dot_edge_lhs(chained.lhs, scope)?);
+                    rhs = *chained.rhs;
+                }
+            }
+        }
+
+        for pair in chain.windows(2) {
+            for lhs_id in &pair[0] {
+                for rhs_id in &pair[1] {
+                    self.add_edge(lhs_id.clone(), rhs_id.clone(), None, attrs.clone())?;
+                }
+            }
+        }
+        Ok(chain.into_iter().flatten().collect())
+    }
+
+    fn add_dot_edge_lhs(
+        &mut self,
+        lhs: graphviz_parser::ast_nodes::EdgeLHS,
+        scope: &DotScope,
+    ) -> Result<Vec<NodeId>> {
+        use graphviz_parser::ast_nodes::EdgeLHS;
+        match lhs {
+            EdgeLHS::Node(node) => {
+                let node_id = NodeId(node.id);
+                self.ensure_dot_node(&node_id, scope)?;
+                Ok(vec![node_id])
+            }
+            EdgeLHS::Subgraph(subgraph) => self.add_dot_subgraph(subgraph, scope),
+        }
+    }
+
+    /// The graph in DOT, with the current positions of the nodes in their pos attributes
+    pub fn to_graphviz(&self) -> String {
+        let (graph_type, edge_op) = if self.directed {
+            ("digraph", "->")
+        } else {
+            ("graph", "--")
+        };
+        let mut dot = format!("{graph_type} {{\n");
+        for (name, value) in &self.attrs {
+            dot += &format!("  {}={};\n", dot_quote(name), dot_quote(value));
+        }
+        for group in self.groups.values().filter(|group| group.parent.is_none()) {
+            self.write_dot_group(&mut dot, group, 1);
+        }
+        for node in self.graph.node_weights() {
+            let mut attrs = node.data.attrs.clone();
+            if node.data.label != node.id.0 {
+                attrs.insert("label".to_string(), node.data.label.clone());
+            }
+            if let Some(Pos(x, y)) = &node.pos {
+                let fixed = if node.pinned { "!" } else { "" };
+                attrs.insert("pos".to_string(), format!("{x:.2},{y:.2}{fixed}"));
+            }
+            dot += &format!("  {}{};\n", dot_quote(&node.id.0), dot_attr_list(&attrs));
+        }
+        for edge in self.graph.edge_references() {
+            let (Ok(a), Ok(b)) = (
+                self.resolve_node_id(edge.source()),
+                self.resolve_node_id(edge.target()),
+            ) else {
+                continue;
+            };
+            dot += &format!(
+                "  {} {edge_op} {}{};\n",
+                dot_quote(&a.0),
+                dot_quote(&b.0),
+                dot_attr_list(&edge.weight().attrs)
+            );
+        }
+        dot += "}\n";
+        dot
+    }
+
+    fn write_dot_group(&self, dot: &mut String, group: &Group, depth: usize) {
+        let indent = "  ".repeat(depth);
+        *dot += &format!("{indent}subgraph {} {{\n", dot_quote(&group.id.0));
+        let mut attrs = group.attrs.clone();
+        if let Some(label) = &group.label {
+            attrs.insert("label".to_string(), label.clone());
+        }
+        for (name, value) in &attrs {
+            *dot += &format!("{indent}  {}={};\n", dot_quote(name), dot_quote(value));
+        }
+        for child in self
+            .groups
+            .values()
+            .filter(|child| child.parent.as_ref() == Some(&group.id))
+        {
+            self.write_dot_group(dot, child, depth + 1);
+        }
+        for node_id in &group.nodes {
+            *dot += &format!("{indent}  {};\n", dot_quote(&node_id.0));
+        }
+        *dot += &format!("{indent}}}\n");
+    }
+}
+
+/// The default attributes set with the node [...] and edge [...] statements, and the group
+/// the statements belong to
+#[derive(Debug, Clone, Default)]
+struct DotScope {
+    node: Attributes,
+    edge: Attributes,
+    group: Option<GroupId>,
+}
+
+// Quotes an identifier or a value for DOT. Backslashes starting an escape sequence, such as
+// \N in labels, are kept as they are. Others are escaped, so that a backslash at the end of
+// a value doesn't escape the closing quote.
+fn dot_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => match chars.next_if(|next| "nlrNGETHL\\".contains(*next)) {
+                Some(next) => {
+                    quoted.push(c);
+                    quoted.push(next);
+                }
+                None => quoted.push_str("\\\\"),
+            },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
 
-fn attr_map(
-    attr_list: &Option<graphviz_parser::ast_nodes::AttributeList>,
-) -> HashMap<&str, &String> {
-    let mut attrs = HashMap::new();
+fn dot_attr_list(attrs: &Attributes) -> String {
+    if attrs.is_empty() {
+        String::new()
+    } else {
+        let attrs: Vec<String> = attrs
+            .iter()
+            .map(|(name, value)| format!("{}={}", dot_quote(name), dot_quote(value)))
+            .collect();
+        format!(" [{}]", attrs.join(", "))
+    }
+}
+
+fn attributes(attr_list: &Option<graphviz_parser::ast_nodes::AttributeList>) -> Attributes {
+    let mut attrs = Attributes::new();
     if let Some(attribute_list) = attr_list {
         for attr_group in attribute_list {
             for assignment in attr_group {
-                attrs.insert(assignment.lhs.as_str(), &assignment.rhs);
+                attrs.insert(assignment.lhs.clone(), assignment.rhs.clone());
             }
         }
     }
     attrs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id(id: &str) -> NodeId {
+        NodeId::from(String::from(id))
+    }
+
+    fn edge_id(id: &str) -> EdgeId {
+        EdgeId::from(String::from(id))
+    }
+
+    // The nodes a to e, with edges named after the nodes they connect
+    fn example() -> Graph {
+        let mut graph = Graph::new();
+        for id in ["a", "b", "c", "d", "e"] {
+            graph.ensure_node(&node_id(id));
+        }
+        for id in ["ab", "bc", "cd", "de", "eb", "ae"] {
+            let (a, b) = id.split_at(1);
+            graph
+                .add_edge(node_id(a), node_id(b), Some(edge_id(id)), Attributes::new())
+                .unwrap();
+        }
+        graph
+    }
+
+    // Every id maps to the index of the node or edge with that id, and the edges still
+    // connect the nodes they're named after
+    fn assert_consistent(graph: &Graph) {
+        assert_eq!(graph.node_id_map.len(), graph.graph.node_count());
+        for (id, &index) in &graph.node_id_map {
+            assert_eq!(&graph.graph[index].id, id);
+        }
+        assert_eq!(graph.edge_id_map.len(), graph.graph.edge_count());
+        for (id, &index) in &graph.edge_id_map {
+            assert_eq!(&graph.graph[index].id, id);
+            let (a, b) = graph.graph.edge_endpoints(index).unwrap();
+            let endpoints = format!("{}{}", graph.graph[a].id.0, graph.graph[b].id.0);
+            assert_eq!(endpoints, id.0);
+        }
+    }
+
+    fn node_ids(graph: &Graph) -> Vec<&str> {
+        let mut ids: Vec<&str> = graph.node_id_map.left_values().map(|id| &*id.0).collect();
+        ids.sort();
+        ids
+    }
+
+    fn edge_ids(graph: &Graph) -> Vec<&str> {
+        let mut ids: Vec<&str> = graph.edge_id_map.left_values().map(|id| &*id.0).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn remove_middle_node() {
+        let mut graph = example();
+        graph.remove_node(&node_id("b")).unwrap();
+        assert_consistent(&graph);
+        assert_eq!(node_ids(&graph), ["a", "c", "d", "e"]);
+        assert_eq!(edge_ids(&graph), ["ae", "cd", "de"]);
+    }
+
+    #[test]
+    fn remove_middle_edge() {
+        let mut graph = example();
+        graph.remove_edge(&edge_id("bc")).unwrap();
+        assert_consistent(&graph);
+        assert_eq!(edge_ids(&graph), ["ab", "ae", "cd", "de", "eb"]);
+        assert!(graph.resolve_edge_index(&edge_id("bc")).is_err());
+
+        graph.remove_edge(&edge_id("ab")).unwrap();
+        graph.remove_node(&node_id("e")).unwrap();
+        assert_consistent(&graph);
+        assert_eq!(edge_ids(&graph), ["cd"]);
+    }
+}
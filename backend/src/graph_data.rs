@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use crate::graph::Graph;
-use crate::layout::Layout;
+use crate::layout::{new_engine, EngineKind, LayoutEngine, LayoutParams};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -24,25 +24,130 @@ pub enum Error {
 
 pub struct GraphData {
     pub graph: Graph,
-    pub layout: Option<Layout>,
+    pub engine: Option<EngineKind>, // selected for this graph, overriding the layout attribute
+    pub seed: Option<u64>,          // selected for this graph, overriding the default seed
+    pub params: LayoutParams,
+    default_engine: EngineKind,
+    default_seed: u64,
+    layout_serial: usize, // increase whenever the layout needs to be started again
+    // The serial of the latest full restart, until a step of a layout that started over has
+    // been applied. Until then the layouts start over instead of being incremental.
+    restart: Option<usize>,
+    // The serial at which the graph was restored with its layout already settled. It isn't
+    // laid out again until the serial changes.
+    settled: Option<usize>,
+}
+
+/// What a layout engine is created from. The graph is a copy, so that the layout can be
+/// computed without holding on to the graph data.
+pub struct LayoutRequest {
+    pub layout_serial: usize,
+    graph: Graph,
+    kind: EngineKind,
+    params: LayoutParams,
+    incremental: bool,
+    seed: u64,
 }
 
 pub type GraphDataType = Arc<Mutex<GraphData>>;
 
 impl GraphData {
+    pub fn new(default_engine: EngineKind, default_seed: u64) -> GraphData {
+        GraphData {
+            graph: Graph::new(),
+            engine: None,
+            seed: None,
+            params: LayoutParams::default(),
+            default_engine,
+            default_seed,
+            layout_serial: 0,
+            restart: None,
+            settled: None,
+        }
+    }
+
+    /// The engine selected for the graph, or by its layout attribute, or the default one
+    pub fn engine_kind(&self) -> EngineKind {
+        self.engine
+            .or_else(|| {
+                self.graph
+                    .attrs()
+                    .get("layout")
+                    .and_then(|layout| EngineKind::from_graphviz(layout))
+            })
+            .unwrap_or(self.default_engine)
+    }
+
+    /// The seed of the random numbers of the layout, which makes the layout repeatable
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or(self.default_seed)
+    }
+
+    /// Lays out the graph again after it has changed, moving the existing nodes as little
+    /// as possible
     pub fn reset_layout(&mut self) {
-        self.layout = None;
+        self.layout_serial += 1;
+    }
+
+    /// Lays out the whole graph again, e.g. after the layout settings have changed
+    pub fn restart_layout(&mut self) {
+        self.layout_serial += 1;
+        self.restart = Some(self.layout_serial);
+    }
+
+    /// Keeps the current positions as they are, as the layout of a restored graph, until the
+    /// graph or the layout settings change
+    pub fn restore_layout(&mut self) {
+        self.layout_serial += 1;
+        self.restart = None;
+        self.settled = Some(self.layout_serial);
+    }
+
+    /// Whether the positions are kept as they were restored
+    pub fn is_settled(&self) -> bool {
+        self.settled == Some(self.layout_serial)
     }
 
     pub fn is_empty(&self) -> bool {
         self.graph.graph.node_count() == 0
     }
 
-    #[allow(clippy::result_large_err)]
-    pub fn update_layout(&mut self) -> Result<&mut Layout, Error> {
-        if self.layout.is_none() {
-            self.layout = Some(Layout::new(&self.graph)?);
+    /// Layouts created before the latest reset are outdated
+    pub fn layout_serial(&self) -> usize {
+        self.layout_serial
+    }
+
+    /// Records that a step of the layout with the serial has been applied, which completes
+    /// the restarts requested before it
+    pub fn layout_applied(&mut self, layout_serial: usize) {
+        if self.restart.is_some_and(|restart| restart <= layout_serial) {
+            self.restart = None;
+        }
+    }
+
+    /// What's needed for starting the layout again. The graph is sorted, so that the layout
+    /// only depends on the graph and the seed.
+    pub fn layout_request(&self) -> LayoutRequest {
+        LayoutRequest {
+            layout_serial: self.layout_serial,
+            graph: self.graph.sorted(),
+            kind: self.engine_kind(),
+            params: self.params.clone(),
+            incremental: self.restart.is_none(),
+            seed: self.seed(),
         }
-        Ok(self.layout.as_mut().unwrap())
+    }
+}
+
+impl LayoutRequest {
+    #[allow(clippy::result_large_err)]
+    pub fn new_engine(&self) -> Result<Box<dyn LayoutEngine>, Error> {
+        Ok(new_engine(
+            &self.graph,
+            self.kind,
+            &self.params,
+            self.incremental,
+            self.seed,
+        )?)
     }
 }
@@ -0,0 +1,321 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::graph::{Attributes, Edge, EdgeId, GraphResponse, Group, Node, NodeId, Pos};
+
+/// Positions are sent rounded to this precision, so that nodes that barely move don't need
+/// to be sent at all
+const POSITION_QUANTUM: f64 = 0.1;
+
+/// The changes between two versions of a graph response. Nodes and edges are identified by
+/// their ids, and the added ones replace any existing ones with the same id.
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct GraphDelta {
+    pub added_nodes: Vec<Node>,
+    pub removed_nodes: Vec<NodeId>,
+    pub moved_nodes: Vec<(NodeId, Pos)>,
+    pub added_edges: Vec<(NodeId, NodeId, Edge)>,
+    pub removed_edges: Vec<EdgeId>,
+    // These are only present when changed
+    pub directed: Option<bool>,
+    pub attrs: Option<Attributes>,
+    pub groups: Option<Vec<Group>>,
+}
+
+impl GraphDelta {
+    pub fn between(old: &GraphResponse, new: &GraphResponse) -> GraphDelta {
+        let mut delta = GraphDelta::default();
+
+        let old_nodes: HashMap<&NodeId, &Node> =
+            old.nodes.iter().map(|node| (&node.id, node)).collect();
+        for node in &new.nodes {
+            match old_nodes.get(&node.id) {
+                Some(old_node) if old_node.data == node.data && old_node.pinned == node.pinned => {
+                    if let (Some(pos), true) = (&node.pos, old_node.pos != node.pos) {
+                        delta.moved_nodes.push((node.id.clone(), pos.clone()));
+                    }
+                }
+                _ => delta.added_nodes.push(node.clone()),
+            }
+        }
+        let new_node_ids: HashSet<&NodeId> = new.nodes.iter().map(|node| &node.id).collect();
+        delta.removed_nodes = old
+            .nodes
+            .iter()
+            .filter(|node| !new_node_ids.contains(&node.id))
+            .map(|node| node.id.clone())
+            .collect();
+
+        let old_edges: HashMap<&EdgeId, &(NodeId, NodeId, Edge)> =
+            old.edges.iter().map(|edge| (&edge.2.id, edge)).collect();
+        for edge in &new.edges {
+            if old_edges.get(&edge.2.id) != Some(&edge) {
+                delta.added_edges.push(edge.clone());
+            }
+        }
+        let new_edge_ids: HashSet<&EdgeId> = new.edges.iter().map(|edge| &edge.2.id).collect();
+        delta.removed_edges = old
+            .edges
+            .iter()
+            .filter(|edge| !new_edge_ids.contains(&edge.2.id))
+            .map(|edge| edge.2.id.clone())
+            .collect();
+
+        if old.directed != new.directed {
+            delta.directed = Some(new.directed);
+        }
+        if old.attrs != new.attrs {
+            delta.attrs = Some(new.attrs.clone());
+        }
+        if old.groups != new.groups {
+            delta.groups = Some(new.groups.clone());
+        }
+
+        delta
+    }
+
+    /// Combines this delta with the one following it, so that applying the result is the
+    /// same as applying the two deltas in order
+    pub fn merge(&mut self, later: GraphDelta) {
+        {
+            // Nodes that are removed or added again don't need their earlier changes
+            let replaced_nodes: HashSet<&NodeId> = later
+                .removed_nodes
+                .iter()
+                .chain(later.added_nodes.iter().map(|node| &node.id))
+                .collect();
+            self.added_nodes
+                .retain(|node| !replaced_nodes.contains(&node.id));
+            self.moved_nodes
+                .retain(|(node_id, _)| !replaced_nodes.contains(node_id));
+
+            let replaced_edges: HashSet<&EdgeId> = later
+                .removed_edges
+                .iter()
+                .chain(later.added_edges.iter().map(|edge| &edge.2.id))
+                .collect();
+            self.added_edges
+                .retain(|edge| !replaced_edges.contains(&edge.2.id));
+        }
+        // Moves are applied after additions, so they may refer to earlier added nodes
+        self.moved_nodes.retain(|(node_id, _)| {
+            !later
+                .moved_nodes
+                .iter()
+                .any(|(moved_id, _)| moved_id == node_id)
+        });
+
+        self.added_nodes.extend(later.added_nodes);
+        self.removed_nodes.extend(later.removed_nodes);
+        self.moved_nodes.extend(later.moved_nodes);
+        self.added_edges.extend(later.added_edges);
+        self.removed_edges.extend(later.removed_edges);
+        if later.directed.is_some() {
+            self.directed = later.directed;
+        }
+        if later.attrs.is_some() {
+            self.attrs = later.attrs;
+        }
+        if later.groups.is_some() {
+            self.groups = later.groups;
+        }
+    }
+
+    /// Applies the changes to a graph, in the same way the clients do. Removals are applied
+    /// before additions, and moves last.
+    pub fn apply_to(self, graph: &mut GraphResponse) {
+        let removed_nodes: HashSet<NodeId> = self.removed_nodes.into_iter().collect();
+        graph.nodes.retain(|node| !removed_nodes.contains(&node.id));
+        let mut node_indices: HashMap<NodeId, usize> = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.id.clone(), index))
+            .collect();
+        for node in self.added_nodes {
+            match node_indices.get(&node.id) {
+                Some(&index) => graph.nodes[index] = node,
+                None => {
+                    node_indices.insert(node.id.clone(), graph.nodes.len());
+                    graph.nodes.push(node);
+                }
+            }
+        }
+        for (node_id, pos) in self.moved_nodes {
+            if let Some(&index) = node_indices.get(&node_id) {
+                graph.nodes[index].pos = Some(pos);
+            }
+        }
+
+        let removed_edges: HashSet<EdgeId> = self.removed_edges.into_iter().collect();
+        graph
+            .edges
+            .retain(|edge| !removed_edges.contains(&edge.2.id));
+        let mut edge_indices: HashMap<EdgeId, usize> = graph
+            .edges
+            .iter()
+            .enumerate()
+            .map(|(index, edge)| (edge.2.id.clone(), index))
+            .collect();
+        for edge in self.added_edges {
+            match edge_indices.get(&edge.2.id) {
+                Some(&index) => graph.edges[index] = edge,
+                None => {
+                    edge_indices.insert(edge.2.id.clone(), graph.edges.len());
+                    graph.edges.push(edge);
+                }
+            }
+        }
+        // Like in the graph responses, edges are only included if both ends are
+        graph.edges.retain(|edge| {
+            node_indices.contains_key(&edge.0) && node_indices.contains_key(&edge.1)
+        });
+
+        if let Some(directed) = self.directed {
+            graph.directed = directed;
+        }
+        if let Some(attrs) = self.attrs {
+            graph.attrs = attrs;
+        }
+        if let Some(groups) = self.groups {
+            graph.groups = groups;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.moved_nodes.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+            && self.directed.is_none()
+            && self.attrs.is_none()
+            && self.groups.is_none()
+    }
+}
+
+/// Rounds the positions of the nodes and the edge routes to POSITION_QUANTUM
+pub fn quantise(graph: &mut GraphResponse) {
+    let positions = graph
+        .nodes
+        .iter_mut()
+        .filter_map(|node| node.pos.as_mut())
+        .chain(
+            graph
+                .edges
+                .iter_mut()
+                .flat_map(|(_a, _b, edge)| &mut edge.route),
+        );
+    for Pos(x, y) in positions {
+        *x = (*x / POSITION_QUANTUM).round() * POSITION_QUANTUM;
+        *y = (*y / POSITION_QUANTUM).round() * POSITION_QUANTUM;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::NodeData;
+
+    fn node(id: &str, label: &str, pos: (f64, f64)) -> Node {
+        Node {
+            id: NodeId::from(String::from(id)),
+            data: NodeData {
+                label: String::from(label),
+                attrs: Attributes::new(),
+            },
+            pos: Some(Pos(pos.0, pos.1)),
+            pinned: false,
+        }
+    }
+
+    fn edge(a: &str, b: &str, color: &str) -> (NodeId, NodeId, Edge) {
+        let attrs = Attributes::from([(String::from("color"), String::from(color))]);
+        (
+            NodeId::from(String::from(a)),
+            NodeId::from(String::from(b)),
+            Edge {
+                id: EdgeId::from(format!("{a}{b}")),
+                attrs,
+                route: Vec::new(),
+            },
+        )
+    }
+
+    fn graph(nodes: Vec<Node>, edges: Vec<(NodeId, NodeId, Edge)>) -> GraphResponse {
+        GraphResponse {
+            nodes,
+            edges,
+            creation_time: 0.0,
+            directed: true,
+            attrs: Attributes::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    // The nodes and edges are identified by their ids, so their order doesn't matter
+    fn normalized(mut graph: GraphResponse) -> serde_json::Value {
+        graph.nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        graph.edges.sort_by(|a, b| a.2.id.cmp(&b.2.id));
+        serde_json::to_value(graph).unwrap()
+    }
+
+    // Applying the merged deltas between the versions gives the same graph as applying them
+    // one by one, which is the last version
+    fn assert_merge_matches(versions: [GraphResponse; 3]) {
+        let [first, second, third] = versions;
+        let earlier = GraphDelta::between(&first, &second);
+        let later = GraphDelta::between(&second, &third);
+
+        let mut one_by_one = first.clone();
+        earlier.clone().apply_to(&mut one_by_one);
+        later.clone().apply_to(&mut one_by_one);
+
+        let mut merged_delta = earlier;
+        merged_delta.merge(later);
+        let mut merged = first;
+        merged_delta.apply_to(&mut merged);
+
+        assert_eq!(normalized(one_by_one), normalized(third.clone()));
+        assert_eq!(normalized(merged), normalized(third));
+    }
+
+    #[test]
+    fn merge_add_then_remove() {
+        let a = node("a", "a", (0.0, 0.0));
+        let b = node("b", "b", (10.0, 0.0));
+        let c = node("c", "c", (20.0, 0.0));
+        assert_merge_matches([
+            graph(vec![a.clone(), b.clone()], vec![edge("a", "b", "red")]),
+            graph(
+                vec![a.clone(), b.clone(), c],
+                vec![edge("a", "b", "red"), edge("b", "c", "blue")],
+            ),
+            graph(
+                vec![node("a", "a", (5.0, 5.0)), b],
+                vec![edge("a", "b", "red")],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn merge_remove_then_add_again() {
+        let a = node("a", "a", (0.0, 0.0));
+        let b = node("b", "b", (10.0, 0.0));
+        let c = node("c", "c", (20.0, 0.0));
+        assert_merge_matches([
+            graph(
+                vec![a.clone(), b, c.clone()],
+                vec![edge("a", "b", "red"), edge("b", "c", "red")],
+            ),
+            graph(vec![a.clone(), c], vec![]),
+            graph(
+                vec![
+                    a,
+                    node("b", "new b", (30.0, 30.0)),
+                    node("c", "c", (25.0, 0.0)),
+                ],
+                vec![edge("a", "b", "green")],
+            ),
+        ]);
+    }
+}
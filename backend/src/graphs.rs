@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::bg_layout::{BgControl, BgLayout};
+use crate::graph_data::{GraphData, GraphDataType};
+use crate::layout::EngineKind;
+
+/// The graph used by the routes that don't name a graph
+pub const DEFAULT_GRAPH: &str = "default";
+
+/// A graph with its own background layout task
+#[derive(Clone)]
+pub struct GraphEntry {
+    pub data: GraphDataType,
+    pub bg_control: BgControl,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct GraphInfo {
+    pub name: String,
+    pub nodes: usize,
+    pub edges: usize,
+}
+
+/// All the graphs served, addressed by their names
+pub struct Graphs {
+    graphs: Mutex<BTreeMap<String, GraphEntry>>,
+    default_engine: EngineKind,
+    default_seed: u64,
+}
+
+pub type GraphsType = Arc<Graphs>;
+
+impl Graphs {
+    pub fn new(default_engine: EngineKind, default_seed: u64) -> GraphsType {
+        Arc::new(Graphs {
+            graphs: Mutex::new(BTreeMap::new()),
+            default_engine,
+            default_seed,
+        })
+    }
+
+    pub async fn get(&self, name: &str) -> Option<GraphEntry> {
+        self.graphs.lock().await.get(name).cloned()
+    }
+
+    pub async fn get_or_create(&self, name: &str) -> GraphEntry {
+        let mut graphs = self.graphs.lock().await;
+        graphs
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let graph_data = Arc::new(Mutex::new(GraphData::new(
+                    self.default_engine,
+                    self.default_seed,
+                )));
+                let bg_control = BgLayout::new(graph_data.clone()).start();
+                GraphEntry {
+                    data: graph_data,
+                    bg_control,
+                }
+            })
+            .clone()
+    }
+
+    /// All the graphs by their names. The graphs can then be locked one at a time, so that
+    /// the registry isn't kept locked for long.
+    pub async fn entries(&self) -> Vec<(String, GraphEntry)> {
+        self.graphs
+            .lock()
+            .await
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect()
+    }
+
+    pub async fn list(&self) -> Vec<GraphInfo> {
+        let mut infos = Vec::new();
+        for (name, entry) in self.entries().await {
+            let data = entry.data.lock().await;
+            infos.push(GraphInfo {
+                name,
+                nodes: data.graph.graph.node_count(),
+                edges: data.graph.graph.edge_count(),
+            });
+        }
+        infos
+    }
+}
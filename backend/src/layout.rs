@@ -1,126 +1,311 @@
 use crate::graph;
-use fjadra::{Link, ManyBody, Node, Simulation, SimulationBuilder};
+use fjadra::Node;
 use petgraph::visit::EdgeRef;
-use petgraph::visit::IntoNodeReferences;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+pub mod circular;
+pub mod force;
+pub mod grid;
+pub mod layered;
+pub mod multilevel;
+mod overlap;
+mod quadtree;
+pub mod radial;
+pub mod routing;
+mod targets;
+
+use force::ForceLayout;
+use multilevel::MultilevelLayout;
+use targets::TargetLayout;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     GraphError(#[from] crate::graph::Error),
+
+    #[error("Invalid layout parameter: {0}")]
+    InvalidParams(String),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub struct Layout {
-    nodes: Vec<graph::Node>,
-    edges: Vec<(graph::NodeId, graph::NodeId, graph::Edge)>,
-    sim: Simulation,
+/// A layout algorithm, working on a snapshot of the graph it was created from
+pub trait LayoutEngine: Send {
+    /// Starts laying out the graph from the current positions of its nodes. An incremental
+    /// layout only adjusts the graph to its latest changes. The random numbers, e.g. for
+    /// placing new nodes, come from rng.
+    fn init(
+        g: &graph::Graph,
+        params: &LayoutParams,
+        incremental: bool,
+        rng: &mut StdRng,
+    ) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Advances the layout. Returns the new positions, and whether the layout has finished.
+    fn step(&mut self) -> (NodesEdges, bool);
+
+    /// Moves the nodes of the graph to the positions from a step, and gives the edges their
+    /// routes. The nodes and edges removed since the layout started are skipped.
+    fn apply(&self, nodes_edges: &NodesEdges, graph: &mut graph::Graph) -> Result<()> {
+        for node in &nodes_edges.nodes {
+            let graph_node = match graph.get_node_mut(&node.id) {
+                Ok(graph_node) => graph_node,
+                Err(graph::Error::NodeNotFound { .. }) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if graph_node.pinned && graph_node.pos.is_some() {
+                // Pinned nodes stay exactly where they were put. Nodes pinned before they had a
+                // position get one from the layout.
+                continue;
+            }
+            if let Some(pos) = &node.pos {
+                graph_node.set_pos(pos.clone());
+            }
+        }
+        for (_a, _b, edge) in &nodes_edges.edges {
+            match graph.get_edge_mut(&edge.id) {
+                Ok(graph_edge) => graph_edge.route = edge.route.clone(),
+                Err(graph::Error::EdgeNotFound { .. }) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The available layout algorithms
+#[derive(
+    serde::Serialize, serde::Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    /// Force-directed layout
+    Force,
+    /// Layers in the direction of the edges, like Graphviz dot
+    Layered,
+    /// Nodes on a circle, like Graphviz circo
+    Circular,
+    /// Nodes in a square grid
+    Grid,
+    /// Tree levels on rings around a root, like Graphviz twopi
+    Radial,
 }
 
-impl From<graph::Node> for Node {
-    fn from(_node: graph::Node) -> Self {
-        Node::default()
+impl EngineKind {
+    /// The engine for the Graphviz layout graph attribute, which names either a Graphviz
+    /// layout program or one of our engines
+    pub fn from_graphviz(layout: &str) -> Option<EngineKind> {
+        match layout {
+            "dot" => Some(EngineKind::Layered),
+            "circo" => Some(EngineKind::Circular),
+            "twopi" => Some(EngineKind::Radial),
+            "neato" | "fdp" | "sfdp" => Some(EngineKind::Force),
+            _ => serde_json::from_value(serde_json::Value::String(layout.to_string())).ok(),
+        }
     }
 }
 
-#[derive(serde::Serialize, Debug, Clone)]
-pub struct NodesEdges {
-    pub nodes: Vec<graph::Node>,
-    pub edges: Vec<(graph::NodeId, graph::NodeId, graph::Edge)>,
+/// The parameters of the force-directed layout
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LayoutParams {
+    /// The initial temperature of the simulation
+    pub alpha: f64,
+    /// The simulation finishes when the temperature drops below this
+    pub alpha_min: f64,
+    /// How much the temperature drops on each step
+    pub alpha_decay: f64,
+    /// How much the nodes slow down on each step, like friction
+    pub velocity_decay: f64,
+    pub link_distance: f64,
+    pub link_strength: f64,
+    pub link_iterations: usize,
+    /// Negative values make the nodes repel each other
+    pub charge_strength: f64,
+    /// The accuracy of the charge force approximation, where smaller is more accurate
+    pub theta: f64,
+    /// The radius within which nodes push each other apart, or 0 for none
+    pub collide_radius: f64,
+    /// How strongly the graph is kept centered, or 0 for not at all
+    pub center_strength: f64,
+    /// The temperature after a change, relative to a full layout, from 0 to 1
+    pub reheat: f64,
+    /// How far the existing nodes may move when the graph changes
+    pub max_movement: f64,
+    /// Graphs with at least this many nodes use the multilevel layout, which is faster. It
+    /// scales its forces and cooling by the charge strength, alpha and alpha decay relative
+    /// to their defaults, and uses the link distance, theta, reheat, max movement and
+    /// overlap removal as they are. The other params only apply to the force-directed
+    /// layout.
+    pub multilevel_threshold: usize,
+    /// Whether the nodes are moved apart when they or their labels overlap, once the
+    /// simulation has finished
+    pub remove_overlaps: bool,
 }
 
-impl Layout {
-    pub fn new(g: &graph::Graph) -> Result<Self> {
-        let edges = g.graph.edge_references();
-        let nodes: Result<Vec<graph::Node>> = g
-            .graph
-            .node_references()
-            .map(|(_node_index, node)| Layout::update_node_pos(node.clone(), g))
-            .collect();
-        let nodes = nodes?;
-        let sim = SimulationBuilder::default()
-            .with_alpha_min(0.5)
-            .build(nodes.iter().map(|node| node.layout_node()))
-            .add_force(
-                "link",
-                Link::new(
-                    edges
-                        .clone()
-                        .map(|edge| (edge.source().index(), edge.target().index())),
-                )
-                .strength(0.1)
-                .distance(30.0)
-                .iterations(1),
-            )
-            .add_force("charge", ManyBody::new());
-        let resolve = |edge: petgraph::graph::EdgeReference<graph::Edge, u32>| -> Result<_> {
-            Ok((
-                g.resolve_node_id(edge.source())?,
-                g.resolve_node_id(edge.target())?,
-                edge.weight().clone(),
-            ))
-        };
-        let edges: Result<Vec<_>> = g.graph.edge_references().map(resolve).collect();
-        Ok(Layout {
-            sim,
-            nodes,
-            edges: edges?,
-        })
+impl Default for LayoutParams {
+    fn default() -> Self {
+        LayoutParams {
+            alpha: 1.0,
+            alpha_min: 0.5,
+            alpha_decay: 1.0 - 0.001f64.powf(1.0 / 300.0),
+            velocity_decay: 0.4,
+            link_distance: 30.0,
+            link_strength: 0.1,
+            link_iterations: 1,
+            charge_strength: -30.0,
+            theta: 0.9,
+            collide_radius: 0.0,
+            center_strength: 0.0,
+            reheat: 0.2,
+            max_movement: 60.0,
+            multilevel_threshold: 2000,
+            remove_overlaps: true,
+        }
     }
+}
 
-    fn update_node_pos(mut node: graph::Node, graph: &graph::Graph) -> Result<graph::Node> {
-        node.pos = match node.pos {
-            None => {
-                let mut average_pos = (0.0f64, 0.0f64);
-                let mut average_count = 0;
-                for neighbour in graph.node_neighbors(&node.id)? {
-                    if let Some(pos) = &neighbour.pos {
-                        average_count += 1;
-                        average_pos.0 += pos.0;
-                        average_pos.1 += pos.1;
-                    }
-                }
-                if average_count > 0 {
-                    Some(graph::Pos(
-                        average_pos.0 / average_count as f64,
-                        average_pos.1 / average_count as f64,
-                    ))
-                } else {
-                    None
-                }
+impl LayoutParams {
+    pub fn validate(&self) -> Result<()> {
+        let ranges = [
+            ("alpha", self.alpha, 0.0, 1.0),
+            ("alpha_min", self.alpha_min, 0.0, 1.0),
+            ("alpha_decay", self.alpha_decay, 0.0, 1.0),
+            ("velocity_decay", self.velocity_decay, 0.0, 1.0),
+            ("link_distance", self.link_distance, 0.0, f64::INFINITY),
+            ("link_strength", self.link_strength, 0.0, f64::INFINITY),
+            ("theta", self.theta, 0.0, f64::INFINITY),
+            ("collide_radius", self.collide_radius, 0.0, f64::INFINITY),
+            ("center_strength", self.center_strength, 0.0, f64::INFINITY),
+            ("reheat", self.reheat, 0.0, 1.0),
+            ("max_movement", self.max_movement, 0.0, f64::INFINITY),
+        ];
+        for (name, value, min, max) in ranges {
+            if !(min..=max).contains(&value) {
+                return Err(Error::InvalidParams(format!(
+                    "{name} is {value}, but it must be between {min} and {max}"
+                )));
             }
-            some_pos => some_pos,
-        };
-        Ok(node)
+        }
+        // The temperature decays towards 0, so the simulation would never finish with these
+        // at 0. Neither would the step length of the multilevel layout cool down without
+        // alpha_decay.
+        for (name, value) in [
+            ("alpha_min", self.alpha_min),
+            ("alpha_decay", self.alpha_decay),
+        ] {
+            if value <= 0.0 {
+                return Err(Error::InvalidParams(format!(
+                    "{name} is {value}, but it must be greater than 0"
+                )));
+            }
+        }
+        if self.alpha_min >= self.alpha {
+            return Err(Error::InvalidParams(format!(
+                "alpha_min is {}, but it must be less than alpha, which is {}",
+                self.alpha_min, self.alpha
+            )));
+        }
+        if self.link_iterations == 0 {
+            return Err(Error::InvalidParams(
+                "link_iterations must be at least 1".to_string(),
+            ));
+        }
+        if !self.charge_strength.is_finite() {
+            return Err(Error::InvalidParams(
+                "charge_strength must be finite".to_string(),
+            ));
+        }
+        Ok(())
     }
+}
 
-    pub fn step(&mut self) -> (NodesEdges, bool) {
-        self.sim.tick(1usize);
-
-        let positions = self.sim.positions();
-
-        let nodes = std::iter::zip(self.nodes.iter(), positions).map(|(node, pos)| graph::Node {
-            pos: Some(graph::Pos(pos[0], pos[1])),
-            ..node.clone()
-        });
-
-        let is_finished = self.sim.is_finished();
+/// Creates the layout engine of the kind for the graph. The seed makes the positions of the
+/// new nodes repeatable.
+pub fn new_engine(
+    g: &graph::Graph,
+    kind: EngineKind,
+    params: &LayoutParams,
+    incremental: bool,
+    seed: u64,
+) -> Result<Box<dyn LayoutEngine>> {
+    fn init<E: LayoutEngine + 'static>(
+        g: &graph::Graph,
+        params: &LayoutParams,
+        incremental: bool,
+        rng: &mut StdRng,
+    ) -> Result<Box<dyn LayoutEngine>> {
+        Ok(Box::new(E::init(g, params, incremental, rng)?))
+    }
 
-        let nodes_edges = NodesEdges {
-            nodes: nodes.collect(),
-            edges: self.edges.clone(),
-        };
+    let mut rng = StdRng::seed_from_u64(seed);
+    let init = match kind {
+        EngineKind::Force if g.graph.node_count() >= params.multilevel_threshold => {
+            init::<MultilevelLayout>
+        }
+        EngineKind::Force => init::<ForceLayout>,
+        EngineKind::Layered => init::<TargetLayout<layered::Layered>>,
+        EngineKind::Circular => init::<TargetLayout<circular::Circular>>,
+        EngineKind::Grid => init::<TargetLayout<grid::Grid>>,
+        EngineKind::Radial => init::<TargetLayout<radial::Radial>>,
+    };
+    init(g, params, incremental, &mut rng)
+}
 
-        (nodes_edges, is_finished)
-    }
+// The edges of the graph by the ids of their endpoints. Their routes are cleared, as they go
+// out of date when the nodes move.
+fn resolve_edges(g: &graph::Graph) -> Result<Vec<(graph::NodeId, graph::NodeId, graph::Edge)>> {
+    let resolve = |edge: petgraph::graph::EdgeReference<graph::Edge, u32>| -> Result<_> {
+        Ok((
+            g.resolve_node_id(edge.source())?,
+            g.resolve_node_id(edge.target())?,
+            graph::Edge {
+                route: Vec::new(),
+                ..edge.weight().clone()
+            },
+        ))
+    };
+    g.graph.edge_references().map(resolve).collect()
+}
 
-    pub fn apply(nodes_edges: &NodesEdges, graph: &mut graph::Graph) -> Result<(), Error> {
-        for node in &nodes_edges.nodes {
-            let graph_node = graph.get_node_mut(&node.id)?;
-            if let Some(pos) = &node.pos {
-                graph_node.set_pos(pos.clone());
+// The node indices in the order of a depth-first traversal, ignoring the edge directions
+fn traversal_order(g: &graph::Graph) -> Vec<usize> {
+    let node_count = g.graph.node_count();
+    let mut visited = vec![false; node_count];
+    let mut order = Vec::with_capacity(node_count);
+    for root in 0..node_count {
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if visited[node] {
+                continue;
             }
+            visited[node] = true;
+            order.push(node);
+            // Reversed, so that the neighbours are visited in their original order
+            let mut neighbours: Vec<usize> = g
+                .graph
+                .neighbors_undirected(petgraph::graph::NodeIndex::new(node))
+                .map(|neighbour| neighbour.index())
+                .filter(|&neighbour| !visited[neighbour])
+                .collect();
+            neighbours.reverse();
+            stack.extend(neighbours);
         }
-        Ok(())
     }
+    order
+}
+
+impl From<graph::Node> for Node {
+    fn from(_node: graph::Node) -> Self {
+        Node::default()
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct NodesEdges {
+    pub nodes: Vec<graph::Node>,
+    pub edges: Vec<(graph::NodeId, graph::NodeId, graph::Edge)>,
 }
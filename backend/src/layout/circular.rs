@@ -0,0 +1,33 @@
+//! Circular layout in the style of Graphviz circo: the nodes on a circle, in the order of a
+//! traversal so that connected nodes tend to be close to each other.
+
+use std::f64::consts::TAU;
+
+use super::targets::Targets;
+use super::traversal_order;
+use crate::graph;
+
+/// Distance between adjacent nodes on the circle
+const NODE_SEPARATION: f64 = 30.0;
+
+/// Nodes on a circle, as the targets of a TargetLayout
+pub struct Circular;
+
+impl Targets for Circular {
+    fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+        positions(g)
+    }
+}
+
+/// The positions of the nodes in the order of their indices
+pub fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+    let order = traversal_order(g);
+    let count = order.len();
+    let radius = (count as f64 * NODE_SEPARATION / TAU).max(NODE_SEPARATION);
+    let mut positions = vec![graph::Pos(0.0, 0.0); count];
+    for (index, node) in order.into_iter().enumerate() {
+        let angle = TAU * index as f64 / count as f64;
+        positions[node] = graph::Pos(radius * angle.cos(), radius * angle.sin());
+    }
+    positions
+}
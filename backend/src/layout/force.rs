@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+
+use fjadra::{Center, Collide, Link, ManyBody, Node, Simulation, SimulationBuilder};
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use super::overlap;
+use super::{resolve_edges, LayoutEngine, LayoutParams, NodesEdges, Result};
+use crate::graph;
+
+/// The angle between successive new nodes placed around the same point, which spreads them
+/// evenly however many there are
+pub(super) const GOLDEN_ANGLE: f64 = 2.399_963_229_728_653;
+
+/// A random point within the radius from the center, any point being equally likely
+pub(super) fn random_around(rng: &mut StdRng, center: (f64, f64), radius: f64) -> (f64, f64) {
+    let angle = rng.random_range(0.0..std::f64::consts::TAU);
+    let distance = radius * rng.random::<f64>().sqrt();
+    (
+        center.0 + distance * angle.cos(),
+        center.1 + distance * angle.sin(),
+    )
+}
+
+/// The force-directed layout.
+///
+/// When nodes are added to a graph that has already been laid out, the layout is
+/// incremental: first the new nodes find their places while the rest stay fixed, and then
+/// the whole graph is relaxed with a lower temperature. During incremental layouts the old
+/// nodes move at most max_movement from where they were.
+pub struct ForceLayout {
+    model: Model,
+    edges: Vec<(graph::NodeId, graph::NodeId, graph::Edge)>,
+    sim: Simulation,
+    /// The temperature of the simulation, for rebuilding it at the same temperature
+    alpha: f64,
+    /// The positions the old nodes had before an incremental layout
+    start_positions: Option<Vec<Option<graph::Pos>>>,
+    /// Whether the new nodes are being placed while the old ones are fixed
+    placing_new_nodes: bool,
+}
+
+// What the simulations are built from
+struct Model {
+    nodes: Vec<graph::Node>,
+    params: LayoutParams,
+    links: Vec<LinkGroup>,
+    clusters: Vec<Vec<NodeIndex>>,
+}
+
+// The links with the same strength and distance, which can share a force
+struct LinkGroup {
+    strength: f64,
+    distance: f64,
+    links: Vec<(usize, usize)>,
+}
+
+impl ForceLayout {
+    /// Places the nodes without a position at the average position of their neighbours,
+    /// spread out a bit so that they don't overlap. Nodes without positioned neighbours go
+    /// around the center of the graph, or anywhere near the origin if there's nothing laid
+    /// out yet.
+    pub(super) fn place_new_nodes(
+        nodes: &mut [graph::Node],
+        g: &graph::Graph,
+        params: &LayoutParams,
+        rng: &mut StdRng,
+    ) -> Result<()> {
+        let center = average_pos(nodes.iter().filter_map(|node| node.pos.as_ref()));
+        let new_nodes = nodes.iter().filter(|node| node.pos.is_none()).count();
+        // The same area per node as with the link distance between neighbours
+        let spread = params.link_distance * (new_nodes as f64).sqrt() / 2.0;
+        let start_angle = rng.random_range(0.0..std::f64::consts::TAU);
+        let mut new_count = 0;
+        for node in nodes.iter_mut().filter(|node| node.pos.is_none()) {
+            let neighbours = g.node_neighbors(&node.id)?;
+            let (around, radius) =
+                match average_pos(neighbours.iter().filter_map(|node| node.pos.as_ref())) {
+                    Some(pos) => (pos, params.link_distance / 2.0),
+                    None => match &center {
+                        Some(pos) => (pos.clone(), params.link_distance),
+                        None => {
+                            let (x, y) = random_around(rng, (0.0, 0.0), spread);
+                            node.pos = Some(graph::Pos(x, y));
+                            continue;
+                        }
+                    },
+                };
+            let angle = start_angle + new_count as f64 * GOLDEN_ANGLE;
+            node.pos = Some(graph::Pos(
+                around.0 + radius * angle.cos(),
+                around.1 + radius * angle.sin(),
+            ));
+            new_count += 1;
+        }
+        Ok(())
+    }
+
+    // Keeps the old nodes within max_movement of where they started. Returns whether any
+    // node was held back.
+    fn limit_movement(&mut self) -> bool {
+        let Some(start_positions) = &self.start_positions else {
+            return false;
+        };
+        let max_movement = self.model.params.max_movement;
+        let mut limited = false;
+        for (node, start) in std::iter::zip(&mut self.model.nodes, start_positions) {
+            if let (Some(pos), Some(start)) = (&mut node.pos, start) {
+                let (dx, dy) = (pos.0 - start.0, pos.1 - start.1);
+                let distance = dx.hypot(dy);
+                if distance > max_movement {
+                    let scale = max_movement / distance;
+                    *pos = graph::Pos(start.0 + dx * scale, start.1 + dy * scale);
+                    limited = true;
+                }
+            }
+        }
+        limited
+    }
+}
+
+impl Model {
+    // The temperature for relaxing the graph after an incremental change, which moves the
+    // nodes less than a full layout
+    fn reheated_alpha(&self) -> f64 {
+        let params = &self.params;
+        params.alpha_min + (params.alpha - params.alpha_min) * params.reheat
+    }
+
+    /// Builds a simulation from the current positions. The nodes marked in fixed stay in
+    /// place, as do the pinned ones.
+    fn build_sim(&self, alpha: f64, fixed: &[bool]) -> Simulation {
+        let params = &self.params;
+        let nodes = self.nodes.iter().enumerate().map(|(index, node)| {
+            match (&node.pos, fixed.get(index)) {
+                (Some(graph::Pos(x, y)), Some(true)) => node.layout_node().fixed_position(*x, *y),
+                _ => node.layout_node(),
+            }
+        });
+
+        // Each cluster gets an invisible anchor node after the actual nodes, and its members
+        // are linked to it
+        let anchors: Vec<Node> = self
+            .clusters
+            .iter()
+            .map(|members| self.cluster_anchor(members))
+            .collect();
+        let cluster_links: Vec<(usize, usize)> = self
+            .clusters
+            .iter()
+            .enumerate()
+            .flat_map(|(cluster_index, members)| {
+                let anchor_index = self.nodes.len() + cluster_index;
+                members
+                    .iter()
+                    .map(move |node_index| (node_index.index(), anchor_index))
+            })
+            .collect();
+
+        let mut sim = SimulationBuilder::default()
+            .with_alpha(alpha)
+            .with_alpha_min(params.alpha_min)
+            .with_alpha_decay(params.alpha_decay)
+            .with_velocity_decay(params.velocity_decay)
+            .build(nodes.chain(anchors));
+        for (index, group) in self.links.iter().enumerate() {
+            sim = sim.add_force(
+                format!("link{index}"),
+                Link::new(group.links.iter().copied())
+                    .strength(group.strength)
+                    .distance(group.distance)
+                    .iterations(params.link_iterations),
+            );
+        }
+        sim = sim.add_force(
+            "charge",
+            ManyBody::new()
+                .strength(params.charge_strength)
+                .theta(params.theta),
+        );
+        if params.collide_radius > 0.0 {
+            let radius = params.collide_radius;
+            sim = sim.add_force("collide", Collide::new().radius(move |_| radius));
+        }
+        if params.center_strength > 0.0 {
+            sim = sim.add_force("center", Center::new().strength(params.center_strength));
+        }
+        if !cluster_links.is_empty() {
+            sim = sim.add_force(
+                "cluster",
+                Link::new(cluster_links)
+                    .strength(0.2)
+                    .distance(10.0)
+                    .iterations(1),
+            );
+        }
+        sim
+    }
+
+    // Anchors start at the center of the members that have a position
+    fn cluster_anchor(&self, members: &[NodeIndex]) -> Node {
+        let positions = members
+            .iter()
+            .filter_map(|node_index| self.nodes.get(node_index.index())?.pos.as_ref());
+        match average_pos(positions) {
+            Some(graph::Pos(x, y)) => Node::default().position(x, y),
+            None => Node::default(),
+        }
+    }
+}
+
+impl LayoutEngine for ForceLayout {
+    /// Starts a layout from the current positions. A full layout moves all the nodes freely,
+    /// while an incremental one keeps the old nodes near their positions.
+    fn init(
+        g: &graph::Graph,
+        params: &LayoutParams,
+        incremental: bool,
+        rng: &mut StdRng,
+    ) -> Result<Self> {
+        let mut nodes: Vec<graph::Node> = g.graph.node_weights().cloned().collect();
+        let is_old: Vec<bool> = nodes.iter().map(|node| node.pos.is_some()).collect();
+        let incremental = incremental && is_old.contains(&true);
+        ForceLayout::place_new_nodes(&mut nodes, g, params, rng)?;
+
+        // The strength and distance are the same for all the links of a force, so the edges
+        // are grouped by them. The weight, len and minlen attributes scale the defaults.
+        let mut link_groups: BTreeMap<(u64, u64), Vec<(usize, usize)>> = BTreeMap::new();
+        for edge in g.graph.edge_references() {
+            let attrs = edge.weight(); // the graph::Edge, not the weight attribute
+            let strength = params.link_strength * attrs.weight();
+            let distance = params.link_distance * attrs.length() * attrs.minlen();
+            link_groups
+                .entry((strength.to_bits(), distance.to_bits()))
+                .or_default()
+                .push((edge.source().index(), edge.target().index()));
+        }
+        let links = link_groups
+            .into_iter()
+            .map(|((strength, distance), links)| LinkGroup {
+                strength: f64::from_bits(strength),
+                distance: f64::from_bits(distance),
+                links,
+            })
+            .collect();
+
+        let start_positions = incremental.then(|| {
+            std::iter::zip(&nodes, &is_old)
+                .map(|(node, &is_old)| node.pos.clone().filter(|_| is_old))
+                .collect()
+        });
+        let model = Model {
+            nodes,
+            params: params.clone(),
+            links,
+            clusters: g.clusters(),
+        };
+        let placing_new_nodes = incremental && is_old.contains(&false);
+        let (alpha, fixed) = if placing_new_nodes {
+            (params.alpha, &is_old[..])
+        } else if incremental {
+            (model.reheated_alpha(), &[][..])
+        } else {
+            (params.alpha, &[][..])
+        };
+        let sim = model.build_sim(alpha, fixed);
+        Ok(ForceLayout {
+            model,
+            edges: resolve_edges(g)?,
+            sim,
+            alpha,
+            start_positions,
+            placing_new_nodes,
+        })
+    }
+
+    fn step(&mut self) -> (NodesEdges, bool) {
+        self.sim.tick(1usize);
+        // The temperature decays towards 0 like in the simulation
+        self.alpha *= 1.0 - self.model.params.alpha_decay;
+
+        // The anchors come after the nodes, so they are left out
+        for (node, pos) in std::iter::zip(&mut self.model.nodes, self.sim.positions()) {
+            node.pos = Some(graph::Pos(pos[0], pos[1]));
+        }
+
+        let mut is_finished = self.sim.is_finished();
+        if self.limit_movement() && !is_finished {
+            // The simulation continues from the positions the nodes were held back to, so
+            // that it doesn't drift away from what is shown
+            self.sim = self.model.build_sim(self.alpha, &[]);
+        }
+        if is_finished && self.placing_new_nodes {
+            // The new nodes are in place, so it's time to relax the whole graph
+            self.placing_new_nodes = false;
+            self.alpha = self.model.reheated_alpha();
+            self.sim = self.model.build_sim(self.alpha, &[]);
+            is_finished = false;
+        }
+        if is_finished && self.model.params.remove_overlaps {
+            overlap::remove_overlaps(&mut self.model.nodes);
+        }
+
+        let nodes_edges = NodesEdges {
+            nodes: self.model.nodes.clone(),
+            edges: self.edges.clone(),
+        };
+
+        (nodes_edges, is_finished)
+    }
+}
+
+fn average_pos<'a>(positions: impl Iterator<Item = &'a graph::Pos>) -> Option<graph::Pos> {
+    let (sum_x, sum_y, count) = positions.fold((0.0, 0.0, 0usize), |(x, y, count), pos| {
+        (x + pos.0, y + pos.1, count + 1)
+    });
+    (count > 0).then(|| graph::Pos(sum_x / count as f64, sum_y / count as f64))
+}
@@ -0,0 +1,34 @@
+//! Grid layout: the nodes in rows of a square grid, in the order of a traversal so that
+//! connected nodes tend to be close to each other.
+
+use super::targets::Targets;
+use super::traversal_order;
+use crate::graph;
+
+/// Distance between adjacent nodes in the grid
+const NODE_SEPARATION: f64 = 40.0;
+
+/// Nodes in a square grid, as the targets of a TargetLayout
+pub struct Grid;
+
+impl Targets for Grid {
+    fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+        positions(g)
+    }
+}
+
+/// The positions of the nodes in the order of their indices
+pub fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+    let order = traversal_order(g);
+    let columns = ((order.len() as f64).sqrt().ceil() as usize).max(1);
+    let mut positions = vec![graph::Pos(0.0, 0.0); order.len()];
+    for (index, node) in order.into_iter().enumerate() {
+        let (row, column) = (index / columns, index % columns);
+        // The y axis points up, so the rows go downwards
+        positions[node] = graph::Pos(
+            column as f64 * NODE_SEPARATION,
+            -(row as f64) * NODE_SEPARATION,
+        );
+    }
+    positions
+}
@@ -0,0 +1,387 @@
+//! Layered layout in the style of Graphviz dot: the edges point from one layer to the next,
+//! with as few crossings as practical. Suits DAGs, such as build graphs and pipelines.
+
+use std::collections::{HashSet, VecDeque};
+
+use petgraph::visit::EdgeRef;
+
+use super::targets::Targets;
+use crate::graph;
+
+/// Distance between adjacent nodes in a layer
+const NODE_SEPARATION: f64 = 30.0;
+/// Distance between adjacent layers
+const LAYER_SEPARATION: f64 = 50.0;
+/// Rounds of reordering the layers to reduce edge crossings
+const ORDERING_SWEEPS: usize = 12;
+/// Rounds of aligning the nodes with their neighbours in the adjacent layers
+const ALIGNMENT_SWEEPS: usize = 8;
+/// The most layers an edge may span, as each layer it crosses takes a dummy node
+const MAX_MINLEN: usize = 100;
+
+/// The direction of the layers, from the rankdir graph attribute
+#[derive(Debug, Clone, Copy)]
+enum RankDir {
+    TopToBottom,
+    BottomToTop,
+    LeftToRight,
+    RightToLeft,
+}
+
+impl RankDir {
+    fn from_attrs(attrs: &graph::Attributes) -> RankDir {
+        match attrs
+            .get("rankdir")
+            .map(|value| value.to_uppercase())
+            .as_deref()
+        {
+            Some("BT") => RankDir::BottomToTop,
+            Some("LR") => RankDir::LeftToRight,
+            Some("RL") => RankDir::RightToLeft,
+            _ => RankDir::TopToBottom,
+        }
+    }
+
+    // The y axis points up, like in Graphviz
+    fn pos(self, layer: usize, x: f64) -> graph::Pos {
+        let depth = layer as f64 * LAYER_SEPARATION;
+        match self {
+            RankDir::TopToBottom => graph::Pos(x, -depth),
+            RankDir::BottomToTop => graph::Pos(x, depth),
+            RankDir::LeftToRight => graph::Pos(depth, -x),
+            RankDir::RightToLeft => graph::Pos(-depth, -x),
+        }
+    }
+}
+
+/// Layers in the direction of the edges, as the targets of a TargetLayout
+pub struct Layered;
+
+impl Targets for Layered {
+    fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+        positions(g)
+    }
+}
+
+/// The positions of the nodes in the order of their indices
+pub fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+    let edges: Vec<(usize, usize, usize)> = g
+        .graph
+        .edge_references()
+        .map(|edge| {
+            // Edges within a layer aren't supported, so each edge spans at least one layer
+            let minlen = edge.weight().minlen();
+            let minlen = if minlen.is_finite() {
+                (minlen.round() as usize).clamp(1, MAX_MINLEN)
+            } else {
+                1
+            };
+            (edge.source().index(), edge.target().index(), minlen)
+        })
+        .collect();
+    let rankdir = RankDir::from_attrs(g.attrs());
+    layered_positions(g.graph.node_count(), &edges)
+        .into_iter()
+        .map(|(layer, x)| rankdir.pos(layer, x))
+        .collect()
+}
+
+/// Returns the layer of each node, and its coordinate within the layer. The edges are given
+/// with the minimum number of layers they span.
+fn layered_positions(node_count: usize, edges: &[(usize, usize, usize)]) -> Vec<(usize, f64)> {
+    let edges = acyclic_edges(node_count, edges);
+    let layers = assign_layers(node_count, &edges);
+    let mut layering = Layering::new(&layers, &edges);
+    layering.order();
+    let coordinates = layering.coordinates();
+    (0..node_count)
+        .map(|node| (layers[node], coordinates[node]))
+        .collect()
+}
+
+/// Reverses the edges that close a cycle, as found by a depth-first search. Self-loops don't
+/// affect the layout, so they are left out.
+fn acyclic_edges(node_count: usize, edges: &[(usize, usize, usize)]) -> Vec<(usize, usize, usize)> {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Visit {
+        New,
+        Active,
+        Done,
+    }
+
+    let mut successors: Vec<Vec<usize>> = vec![Vec::new(); node_count];
+    for &(a, b, _minlen) in edges {
+        successors[a].push(b);
+    }
+    let mut visits = vec![Visit::New; node_count];
+    let mut back_edges: HashSet<(usize, usize)> = HashSet::new();
+    for root in 0..node_count {
+        if visits[root] != Visit::New {
+            continue;
+        }
+        visits[root] = Visit::Active;
+        // The nodes on the current path, with the index of their next successor to visit
+        let mut path = vec![(root, 0usize)];
+        while let Some(&(node, next)) = path.last() {
+            match successors[node].get(next) {
+                Some(&successor) => {
+                    let top = path.len() - 1;
+                    path[top].1 += 1;
+                    match visits[successor] {
+                        Visit::New => {
+                            visits[successor] = Visit::Active;
+                            path.push((successor, 0));
+                        }
+                        Visit::Active => {
+                            back_edges.insert((node, successor));
+                        }
+                        Visit::Done => (),
+                    }
+                }
+                None => {
+                    visits[node] = Visit::Done;
+                    path.pop();
+                }
+            }
+        }
+    }
+    edges
+        .iter()
+        .filter(|(a, b, _minlen)| a != b)
+        .map(|&(a, b, minlen)| {
+            if back_edges.contains(&(a, b)) {
+                (b, a, minlen)
+            } else {
+                (a, b, minlen)
+            }
+        })
+        .collect()
+}
+
+/// Puts each node as close below its predecessors as their edges allow. Nodes without
+/// predecessors are then moved down next to their successors.
+fn assign_layers(node_count: usize, edges: &[(usize, usize, usize)]) -> Vec<usize> {
+    let mut successors: Vec<Vec<(usize, usize)>> = vec![Vec::new(); node_count];
+    let mut in_degrees = vec![0usize; node_count];
+    for &(a, b, minlen) in edges {
+        successors[a].push((b, minlen));
+        in_degrees[b] += 1;
+    }
+    let sources: Vec<usize> = (0..node_count)
+        .filter(|&node| in_degrees[node] == 0)
+        .collect();
+
+    let mut layers = vec![0usize; node_count];
+    let mut queue: VecDeque<usize> = sources.iter().copied().collect();
+    while let Some(node) = queue.pop_front() {
+        for &(successor, minlen) in &successors[node] {
+            layers[successor] = layers[successor].max(layers[node] + minlen);
+            in_degrees[successor] -= 1;
+            if in_degrees[successor] == 0 {
+                queue.push_back(successor);
+            }
+        }
+    }
+
+    for source in sources {
+        if let Some(layer) = successors[source]
+            .iter()
+            .map(|&(node, minlen)| layers[node] - minlen)
+            .min()
+        {
+            layers[source] = layer;
+        }
+    }
+    layers
+}
+
+/// The nodes in their layers. Edges spanning several layers go through dummy nodes, one on
+/// each layer in between, which come after the actual nodes.
+struct Layering {
+    layers: Vec<Vec<usize>>, // in their order within the layer
+    upper: Vec<Vec<usize>>,  // the neighbours of each node on the layer above
+    lower: Vec<Vec<usize>>,  // the neighbours of each node on the layer below
+    node_count: usize,       // including the dummy nodes
+}
+
+impl Layering {
+    fn new(node_layers: &[usize], edges: &[(usize, usize, usize)]) -> Layering {
+        let mut node_layers = node_layers.to_vec();
+        let mut upper: Vec<Vec<usize>> = vec![Vec::new(); node_layers.len()];
+        let mut lower: Vec<Vec<usize>> = vec![Vec::new(); node_layers.len()];
+        for &(a, b, _minlen) in edges {
+            let mut previous = a;
+            for layer in node_layers[a] + 1..node_layers[b] {
+                let dummy = node_layers.len();
+                node_layers.push(layer);
+                upper.push(vec![previous]);
+                lower.push(Vec::new());
+                lower[previous].push(dummy);
+                previous = dummy;
+            }
+            lower[previous].push(b);
+            upper[b].push(previous);
+        }
+
+        let layer_count = node_layers.iter().max().map_or(0, |&layer| layer + 1);
+        let mut layers: Vec<Vec<usize>> = vec![Vec::new(); layer_count];
+        for (node, &layer) in node_layers.iter().enumerate() {
+            layers[layer].push(node);
+        }
+        Layering {
+            layers,
+            upper,
+            lower,
+            node_count: node_layers.len(),
+        }
+    }
+
+    // The index of each node within its layer
+    fn indices(&self) -> Vec<usize> {
+        let mut indices = vec![0usize; self.node_count];
+        for layer in &self.layers {
+            for (index, &node) in layer.iter().enumerate() {
+                indices[node] = index;
+            }
+        }
+        indices
+    }
+
+    /// Orders the layers to reduce crossings, by sorting the nodes by the average index of
+    /// their neighbours, alternately downwards and upwards
+    fn order(&mut self) {
+        let mut best = self.layers.clone();
+        let mut best_crossings = self.crossings();
+        for sweep in 0..ORDERING_SWEEPS {
+            let downwards = sweep % 2 == 0;
+            for layer in self.sweep_layers(downwards) {
+                self.sort_by_barycenter(layer, downwards);
+            }
+            let crossings = self.crossings();
+            if crossings < best_crossings {
+                best = self.layers.clone();
+                best_crossings = crossings;
+            }
+        }
+        self.layers = best;
+    }
+
+    // The layers to go through when sweeping downwards or upwards, skipping the first one
+    fn sweep_layers(&self, downwards: bool) -> Vec<usize> {
+        let count = self.layers.len();
+        if downwards {
+            (1..count).collect()
+        } else {
+            (0..count.saturating_sub(1)).rev().collect()
+        }
+    }
+
+    fn sort_by_barycenter(&mut self, layer: usize, downwards: bool) {
+        let indices = self.indices();
+        let neighbours = if downwards { &self.upper } else { &self.lower };
+        let mut keyed: Vec<(f64, usize)> = self.layers[layer]
+            .iter()
+            .map(|&node| {
+                let key = average(neighbours[node].iter().map(|&n| indices[n] as f64))
+                    .unwrap_or(indices[node] as f64);
+                (key, node)
+            })
+            .collect();
+        keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.layers[layer] = keyed.into_iter().map(|(_key, node)| node).collect();
+    }
+
+    /// The number of edge crossings between each pair of adjacent layers. The edges are taken
+    /// in order of their upper ends, counting the earlier edges whose lower ends are further
+    /// along with an accumulator tree, for O(E log V) per pair of layers.
+    fn crossings(&self) -> usize {
+        let indices = self.indices();
+        self.layers
+            .iter()
+            .map(|layer| {
+                let mut edges: Vec<(usize, usize)> = layer
+                    .iter()
+                    .flat_map(|&node| {
+                        let indices = &indices;
+                        self.lower[node]
+                            .iter()
+                            .map(move |&lower| (indices[node], indices[lower]))
+                    })
+                    .collect();
+                edges.sort_unstable();
+                let size = edges.iter().map(|&(_upper, lower)| lower + 1).max();
+                let mut tree = vec![0usize; size.unwrap_or(0) + 1];
+                let mut crossings = 0;
+                for (count, &(_upper, lower)) in edges.iter().enumerate() {
+                    // The earlier edges ending at or before this one don't cross it
+                    let mut index = lower + 1;
+                    let mut not_crossing = 0;
+                    while index > 0 {
+                        not_crossing += tree[index];
+                        index &= index - 1;
+                    }
+                    crossings += count - not_crossing;
+                    let mut index = lower + 1;
+                    while index < tree.len() {
+                        tree[index] += 1;
+                        index += index & index.wrapping_neg();
+                    }
+                }
+                crossings
+            })
+            .sum()
+    }
+
+    /// The coordinates of the nodes within their layers. The nodes are moved towards their
+    /// neighbours, while keeping them apart and in order.
+    fn coordinates(&self) -> Vec<f64> {
+        let mut coordinates = vec![0.0f64; self.node_count];
+        for layer in &self.layers {
+            for (index, &node) in layer.iter().enumerate() {
+                coordinates[node] = index as f64 * NODE_SEPARATION;
+            }
+        }
+        for sweep in 0..ALIGNMENT_SWEEPS {
+            let downwards = sweep % 2 == 0;
+            let neighbours = if downwards { &self.upper } else { &self.lower };
+            for layer in self.sweep_layers(downwards) {
+                let desired: Vec<f64> = self.layers[layer]
+                    .iter()
+                    .map(|&node| {
+                        average(neighbours[node].iter().map(|&n| coordinates[n]))
+                            .unwrap_or(coordinates[node])
+                    })
+                    .collect();
+                for (&node, coordinate) in self.layers[layer].iter().zip(separate(&desired)) {
+                    coordinates[node] = coordinate;
+                }
+            }
+        }
+        coordinates
+    }
+}
+
+fn average(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0usize), |(sum, count), value| {
+        (sum + value, count + 1)
+    });
+    (count > 0).then(|| sum / count as f64)
+}
+
+// Moves the ordered coordinates apart so they are at least NODE_SEPARATION from each other,
+// by averaging the results of pushing them apart to the right and to the left
+fn separate(desired: &[f64]) -> Vec<f64> {
+    let mut rightwards = desired.to_vec();
+    for index in 1..rightwards.len() {
+        rightwards[index] = rightwards[index].max(rightwards[index - 1] + NODE_SEPARATION);
+    }
+    let mut leftwards = desired.to_vec();
+    for index in (0..leftwards.len().saturating_sub(1)).rev() {
+        leftwards[index] = leftwards[index].min(leftwards[index + 1] - NODE_SEPARATION);
+    }
+    rightwards
+        .iter()
+        .zip(&leftwards)
+        .map(|(right, left)| (right + left) / 2.0)
+        .collect()
+}
@@ -0,0 +1,493 @@
+//! Multilevel force-directed layout for large graphs, in the style of Graphviz sfdp. The
+//! graph is coarsened by merging neighbouring nodes until only a few are left, and the
+//! layout of each coarser graph is the starting point for the finer one. The repulsion
+//! between the nodes is approximated with a quadtree. The members of each cluster are
+//! pulled towards the cluster's center, like in the force-directed layout.
+
+use std::collections::BTreeMap;
+
+use petgraph::visit::EdgeRef;
+use rand::rngs::StdRng;
+
+use super::force::{random_around, ForceLayout, GOLDEN_ANGLE};
+use super::overlap;
+use super::quadtree::QuadTree;
+use super::{resolve_edges, LayoutEngine, LayoutParams, NodesEdges, Result};
+use crate::graph;
+
+/// Coarsening stops when a graph has this many nodes or fewer
+const COARSEST_SIZE: usize = 50;
+/// Coarsening stops when merging no longer shrinks the graph below this portion
+const COARSENING_RATIO: f64 = 0.75;
+/// The strength of the repulsion relative to the attraction, at the default charge strength
+const REPULSION: f64 = 0.2;
+/// The strength of the pull towards the center of a cluster, relative to an edge
+const CLUSTER_STRENGTH: f64 = 0.5;
+/// How much the step length changes between iterations, at the default alpha decay
+const COOLING: f64 = 0.9;
+/// A level is done when the step length drops below this portion of the link distance
+const TOLERANCE: f64 = 0.01;
+/// A level is done after this many iterations at the latest
+const MAX_ITERATIONS: usize = 200;
+/// The larger levels start from the layout of the smaller ones, so they get fewer
+/// iterations: this many divided by the number of nodes, but at least MIN_ITERATIONS
+const LEVEL_WORK: usize = 1_000_000;
+const MIN_ITERATIONS: usize = 20;
+/// The smallest number of nodes worth a thread of their own
+const MIN_CHUNK_SIZE: usize = 1000;
+/// The number of nodes times iterations to compute on each step
+const WORK_PER_STEP: usize = 200_000;
+
+/// The multilevel layout. Like the force-directed one, it is incremental when nodes are
+/// added to a graph that has already been laid out, in which case the graph isn't
+/// coarsened.
+pub struct MultilevelLayout {
+    nodes: Vec<graph::Node>,
+    edges: Vec<(graph::NodeId, graph::NodeId, graph::Edge)>,
+    levels: Vec<Level>,         // from the actual graph to the coarsest one
+    level: usize,               // the level being laid out
+    positions: Vec<(f64, f64)>, // of the nodes on that level
+    containers: Vec<usize>,     // the node on the current level containing each actual node
+    /// The positions the old nodes had before an incremental layout
+    start_positions: Option<Vec<Option<(f64, f64)>>>,
+    link_distance: f64,
+    repulsion: f64,
+    cooling: f64,
+    theta: f64,
+    max_movement: f64,
+    remove_overlaps: bool,
+    step_length: f64,
+    progress: usize, // the number of iterations in a row that reduced the energy
+    energy: f64,
+    iterations: usize, // on the current level
+}
+
+// A graph on one level, whose nodes stand for one or more nodes of the finer level
+struct Level {
+    masses: Vec<f64>,                // the number of actual nodes in each node
+    pinned: Vec<Option<(f64, f64)>>, // the positions of the pinned nodes
+    springs: Vec<Spring>,
+    clusters: Vec<Vec<usize>>, // the nodes containing the members of each cluster
+    parents: Vec<usize>,       // the node on the next coarser level containing each node
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Spring {
+    a: usize,
+    b: usize,
+    strength: f64,
+    length: f64,
+}
+
+impl MultilevelLayout {
+    // Keeps the pinned nodes of the current level in place
+    fn pin(&mut self) {
+        for (pos, pinned) in std::iter::zip(&mut self.positions, &self.levels[self.level].pinned) {
+            if let Some(pinned) = pinned {
+                *pos = *pinned;
+            }
+        }
+    }
+
+    /// The repulsion between all the nodes on the current level, inversely proportional to
+    /// their distance. The nodes are divided between threads.
+    fn repulsion(&self) -> Vec<(f64, f64)> {
+        let masses = &self.levels[self.level].masses;
+        let positions = &self.positions;
+        let tree = QuadTree::new(positions, masses);
+        let strength = self.repulsion * self.link_distance * self.link_distance;
+        let node_repulsion = |node: usize| {
+            let mut force = (0.0, 0.0);
+            tree.for_each_body(node, positions, masses, self.theta, |dx, dy, other| {
+                let distance_squared = dx * dx + dy * dy;
+                if distance_squared > 0.0 {
+                    let f = strength * masses[node] * other / distance_squared;
+                    force = (force.0 - dx * f, force.1 - dy * f);
+                }
+            });
+            force
+        };
+
+        let mut forces = vec![(0.0, 0.0); positions.len()];
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let chunk_size = positions.len().div_ceil(threads).max(MIN_CHUNK_SIZE);
+        std::thread::scope(|scope| {
+            for (index, chunk) in forces.chunks_mut(chunk_size).enumerate() {
+                let node_repulsion = &node_repulsion;
+                scope.spawn(move || {
+                    for (offset, force) in chunk.iter_mut().enumerate() {
+                        *force = node_repulsion(index * chunk_size + offset);
+                    }
+                });
+            }
+        });
+        forces
+    }
+
+    /// Moves each node by the step length in the direction of the forces acting on it, as in
+    /// Yifan Hu's "Efficient and high quality force-directed graph drawing". Returns whether
+    /// the level is done.
+    fn iterate(&mut self) -> bool {
+        let level = &self.levels[self.level];
+        let k = self.link_distance;
+        let mut forces = self.repulsion();
+        for spring in &level.springs {
+            let (a, b) = (self.positions[spring.a], self.positions[spring.b]);
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            // Attraction proportional to the square of the distance
+            let f = spring.strength * dx.hypot(dy) / (k * spring.length);
+            forces[spring.a] = (forces[spring.a].0 + dx * f, forces[spring.a].1 + dy * f);
+            forces[spring.b] = (forces[spring.b].0 - dx * f, forces[spring.b].1 - dy * f);
+        }
+        // The members of a cluster are attracted to its center, weighted by their masses
+        for members in &level.clusters {
+            let (x, y, mass) = members.iter().fold((0.0, 0.0, 0.0), |sum, &node| {
+                let (pos, mass) = (self.positions[node], level.masses[node]);
+                (sum.0 + pos.0 * mass, sum.1 + pos.1 * mass, sum.2 + mass)
+            });
+            let center = (x / mass, y / mass);
+            for &node in members {
+                let pos = self.positions[node];
+                let (dx, dy) = (center.0 - pos.0, center.1 - pos.1);
+                let f = CLUSTER_STRENGTH * level.masses[node] * dx.hypot(dy) / k;
+                forces[node] = (forces[node].0 + dx * f, forces[node].1 + dy * f);
+            }
+        }
+
+        let mut energy = 0.0;
+        for ((pos, force), pinned) in self.positions.iter_mut().zip(&forces).zip(&level.pinned) {
+            let magnitude = force.0.hypot(force.1);
+            if pinned.is_none() && magnitude > 0.0 {
+                let scale = self.step_length / magnitude;
+                *pos = (pos.0 + force.0 * scale, pos.1 + force.1 * scale);
+            }
+            energy += magnitude * magnitude;
+        }
+        self.limit_movement();
+
+        // The step length grows while the energy keeps decreasing, and shrinks otherwise
+        if energy < self.energy {
+            self.progress += 1;
+            if self.progress >= 5 {
+                self.progress = 0;
+                self.step_length /= self.cooling;
+            }
+        } else {
+            self.progress = 0;
+            self.step_length *= self.cooling;
+        }
+        self.energy = energy;
+        self.iterations += 1;
+        let max_iterations =
+            (LEVEL_WORK / self.positions.len().max(1)).clamp(MIN_ITERATIONS, MAX_ITERATIONS);
+        self.step_length < TOLERANCE * k || self.iterations >= max_iterations
+    }
+
+    // Keeps the old nodes within max_movement of where they started
+    fn limit_movement(&mut self) {
+        let Some(start_positions) = &self.start_positions else {
+            return;
+        };
+        for (pos, start) in std::iter::zip(&mut self.positions, start_positions) {
+            if let Some(start) = start {
+                let (dx, dy) = (pos.0 - start.0, pos.1 - start.1);
+                let distance = dx.hypot(dy);
+                if distance > self.max_movement {
+                    let scale = self.max_movement / distance;
+                    *pos = (start.0 + dx * scale, start.1 + dy * scale);
+                }
+            }
+        }
+    }
+
+    // Moves on to the next finer level, where the nodes start around the node containing them
+    fn refine(&mut self) {
+        self.level -= 1;
+        let parents = &self.levels[self.level].parents;
+        self.positions = parents
+            .iter()
+            .enumerate()
+            .map(|(node, &parent)| around(self.positions[parent], node, self.link_distance / 10.0))
+            .collect();
+        self.containers = containers(&self.levels, self.level);
+        self.pin();
+        self.step_length = self.link_distance;
+        self.progress = 0;
+        self.energy = f64::INFINITY;
+        self.iterations = 0;
+    }
+}
+
+impl LayoutEngine for MultilevelLayout {
+    fn init(
+        g: &graph::Graph,
+        params: &LayoutParams,
+        incremental: bool,
+        rng: &mut StdRng,
+    ) -> Result<Self> {
+        let mut nodes: Vec<graph::Node> = g.graph.node_weights().cloned().collect();
+        let is_old: Vec<bool> = nodes.iter().map(|node| node.pos.is_some()).collect();
+        let incremental = incremental && is_old.contains(&true);
+        if incremental {
+            ForceLayout::place_new_nodes(&mut nodes, g, params, rng)?;
+        }
+        let start_positions = incremental.then(|| {
+            std::iter::zip(&nodes, &is_old)
+                .map(|(node, &is_old)| {
+                    node.pos
+                        .as_ref()
+                        .filter(|_| is_old)
+                        .map(|pos| (pos.0, pos.1))
+                })
+                .collect()
+        });
+
+        let mut levels = vec![Level::new(g, &nodes)];
+        if !incremental {
+            while let Some(coarser) = levels.last_mut().and_then(Level::coarsen) {
+                levels.push(coarser);
+            }
+        }
+
+        // The coarsest graph starts from the average positions of the nodes it contains,
+        // and the nodes without positions are scattered around them
+        let coarsest = levels.len() - 1;
+        let containers = containers(&levels, coarsest);
+        let mut sums = vec![(0.0, 0.0, 0usize); levels[coarsest].masses.len()];
+        for (node, &container) in std::iter::zip(&nodes, &containers) {
+            if let Some(pos) = &node.pos {
+                let sum = &mut sums[container];
+                *sum = (sum.0 + pos.0, sum.1 + pos.1, sum.2 + 1);
+            }
+        }
+        let center = sums
+            .iter()
+            .fold((0.0, 0.0, 0usize), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+        let center = match center.2 {
+            0 => (0.0, 0.0),
+            count => (center.0 / count as f64, center.1 / count as f64),
+        };
+        let unplaced = sums.iter().filter(|sum| sum.2 == 0).count();
+        let spread = params.link_distance * (unplaced as f64).sqrt();
+        let positions = sums
+            .iter()
+            .map(|&(x, y, count)| match count {
+                0 => random_around(rng, center, spread),
+                count => (x / count as f64, y / count as f64),
+            })
+            .collect();
+
+        // The charge strength, alpha and alpha decay are relative to their defaults, as the
+        // forces and the cooling differ from the force-directed layout
+        let defaults = LayoutParams::default();
+        let step_length = if incremental {
+            params.link_distance * params.alpha * params.reheat
+        } else {
+            params.link_distance * params.alpha * (levels[coarsest].masses.len() as f64).sqrt()
+        };
+        let mut layout = MultilevelLayout {
+            nodes,
+            edges: resolve_edges(g)?,
+            levels,
+            level: coarsest,
+            positions,
+            containers,
+            start_positions,
+            link_distance: params.link_distance.max(f64::EPSILON),
+            repulsion: REPULSION * params.charge_strength / defaults.charge_strength,
+            cooling: COOLING.powf(params.alpha_decay / defaults.alpha_decay),
+            theta: params.theta,
+            max_movement: params.max_movement,
+            remove_overlaps: params.remove_overlaps,
+            step_length,
+            progress: 0,
+            energy: f64::INFINITY,
+            iterations: 0,
+        };
+        layout.pin();
+        Ok(layout)
+    }
+
+    fn step(&mut self) -> (NodesEdges, bool) {
+        let mut work = 0;
+        let mut is_finished = false;
+        while work < WORK_PER_STEP {
+            work += self.positions.len();
+            if self.iterate() {
+                if self.level == 0 {
+                    is_finished = true;
+                    break;
+                }
+                self.refine();
+            }
+        }
+
+        // The nodes are shown at the positions of the nodes containing them
+        for (node, &container) in std::iter::zip(&mut self.nodes, &self.containers) {
+            let (x, y) = self.positions[container];
+            node.pos = Some(graph::Pos(x, y));
+        }
+        if is_finished && self.remove_overlaps {
+            overlap::remove_overlaps(&mut self.nodes);
+        }
+        let nodes_edges = NodesEdges {
+            nodes: self.nodes.clone(),
+            edges: self.edges.clone(),
+        };
+        (nodes_edges, is_finished)
+    }
+}
+
+impl Level {
+    fn new(g: &graph::Graph, nodes: &[graph::Node]) -> Level {
+        let springs = g
+            .graph
+            .edge_references()
+            .filter(|edge| edge.source() != edge.target())
+            .map(|edge| Spring {
+                a: edge.source().index(),
+                b: edge.target().index(),
+                strength: edge.weight().weight(),
+                length: (edge.weight().length() * edge.weight().minlen()).max(f64::EPSILON),
+            })
+            .collect();
+        let clusters = g
+            .clusters()
+            .into_iter()
+            .map(|members| members.iter().map(|node| node.index()).collect())
+            .collect();
+        Level {
+            masses: vec![1.0; nodes.len()],
+            pinned: nodes
+                .iter()
+                .map(|node| match (&node.pos, node.pinned) {
+                    (Some(pos), true) => Some((pos.0, pos.1)),
+                    _ => None,
+                })
+                .collect(),
+            springs,
+            clusters,
+            parents: Vec::new(),
+        }
+    }
+
+    /// Merges the nodes pairwise along the edges, preferring the strong edges between light
+    /// nodes. Pinned nodes aren't merged. Returns None, when the graph is small enough or
+    /// doesn't shrink much anymore.
+    fn coarsen(&mut self) -> Option<Level> {
+        let node_count = self.masses.len();
+        if node_count <= COARSEST_SIZE {
+            return None;
+        }
+        let mut neighbours: Vec<Vec<(usize, f64)>> = vec![Vec::new(); node_count];
+        for spring in &self.springs {
+            neighbours[spring.a].push((spring.b, spring.strength));
+            neighbours[spring.b].push((spring.a, spring.strength));
+        }
+
+        // Nodes with few neighbours go first, so that they aren't left without a partner
+        let mut order: Vec<usize> = (0..node_count).collect();
+        order.sort_by_key(|&node| neighbours[node].len());
+        let mut parents = vec![usize::MAX; node_count];
+        let mut count = 0;
+        for node in order {
+            if parents[node] != usize::MAX {
+                continue;
+            }
+            parents[node] = count;
+            if self.pinned[node].is_none() {
+                let partner = neighbours[node]
+                    .iter()
+                    .filter(|(other, _)| {
+                        parents[*other] == usize::MAX && self.pinned[*other].is_none()
+                    })
+                    .max_by(|a, b| {
+                        let score = |(other, strength): &(usize, f64)| {
+                            strength / (self.masses[node] + self.masses[*other])
+                        };
+                        score(a).total_cmp(&score(b))
+                    });
+                if let Some(&(partner, _)) = partner {
+                    parents[partner] = count;
+                }
+            }
+            count += 1;
+        }
+        if count as f64 > node_count as f64 * COARSENING_RATIO {
+            return None;
+        }
+
+        let mut masses = vec![0.0; count];
+        let mut pinned = vec![None; count];
+        for node in 0..node_count {
+            masses[parents[node]] += self.masses[node];
+            pinned[parents[node]] = self.pinned[node];
+        }
+        // The springs between the same nodes are combined, with their lengths weighted by
+        // their strengths
+        let mut combined: BTreeMap<(usize, usize), (f64, f64)> = BTreeMap::new();
+        for spring in &self.springs {
+            let (a, b) = (parents[spring.a], parents[spring.b]);
+            if a != b {
+                let entry = combined.entry((a.min(b), a.max(b))).or_default();
+                *entry = (
+                    entry.0 + spring.strength,
+                    entry.1 + spring.strength * spring.length,
+                );
+            }
+        }
+        let springs = combined
+            .into_iter()
+            .map(|((a, b), (strength, weighted_length))| Spring {
+                a,
+                b,
+                strength,
+                length: if strength > 0.0 {
+                    weighted_length / strength
+                } else {
+                    1.0
+                },
+            })
+            .collect();
+        // A cluster whose members have all been merged together has nothing left to pull
+        let clusters = self
+            .clusters
+            .iter()
+            .map(|members| {
+                let mut members: Vec<usize> = members.iter().map(|&node| parents[node]).collect();
+                members.sort_unstable();
+                members.dedup();
+                members
+            })
+            .filter(|members| members.len() > 1)
+            .collect();
+        self.parents = parents;
+        Some(Level {
+            masses,
+            pinned,
+            springs,
+            clusters,
+            parents: Vec::new(),
+        })
+    }
+}
+
+// The node on the given level containing each actual node
+fn containers(levels: &[Level], level: usize) -> Vec<usize> {
+    let mut containers: Vec<usize> = (0..levels[0].masses.len()).collect();
+    for finer in &levels[..level] {
+        for container in &mut containers {
+            *container = finer.parents[*container];
+        }
+    }
+    containers
+}
+
+// The nth point on a circle around the center, in a different direction for each n
+fn around(center: (f64, f64), n: usize, radius: f64) -> (f64, f64) {
+    let angle = n as f64 * GOLDEN_ANGLE;
+    (
+        center.0 + radius * angle.cos(),
+        center.1 + radius * angle.sin(),
+    )
+}
@@ -0,0 +1,248 @@
+//! Overlap removal for the nodes and their labels, in the style of the scan-line algorithm.
+//! Each pair of overlapping boxes is separated either horizontally or vertically, whichever
+//! needs less movement, and the nodes keep their order along that axis.
+
+use crate::graph;
+
+/// The radius of the circles drawn for the nodes
+const NODE_RADIUS: f64 = 5.0;
+/// The distance of the start of a label from the center of its node
+const LABEL_OFFSET: f64 = 7.0;
+/// The font size of the labels, unless given by the fontsize attribute
+const FONT_SIZE: f64 = 10.0;
+/// The width of an average character relative to the font size
+const CHAR_WIDTH: f64 = 0.6;
+/// Space left between the boxes
+const MARGIN: f64 = 2.0;
+/// Overlaps smaller than this are ignored
+const TOLERANCE: f64 = 1e-6;
+/// Rounds of separating the boxes. Moving the boxes apart may make them overlap with others,
+/// so a few rounds may be needed.
+const MAX_ROUNDS: usize = 10;
+
+// The space taken by a node and its label, relative to the position of the node
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Extent {
+    left: f64,
+    right: f64,
+    half_height: f64,
+}
+
+impl Extent {
+    /// The label is drawn to the right of the node, vertically centered
+    pub(super) fn of(node: &graph::Node) -> Extent {
+        let font_size = node
+            .data
+            .attrs
+            .get("fontsize")
+            .and_then(|size| size.parse::<f64>().ok())
+            .filter(|size| *size > 0.0)
+            .unwrap_or(FONT_SIZE);
+        let lines: Vec<&str> = node.data.label.lines().collect();
+        let longest = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let label_width = longest as f64 * font_size * CHAR_WIDTH;
+        Extent {
+            left: NODE_RADIUS + MARGIN / 2.0,
+            right: LABEL_OFFSET.max(NODE_RADIUS) + label_width + MARGIN / 2.0,
+            half_height: NODE_RADIUS.max(lines.len() as f64 * font_size / 2.0) + MARGIN / 2.0,
+        }
+    }
+
+    // Half of the width and the height of the box
+    pub(super) fn half_size(&self) -> [f64; 2] {
+        [(self.left + self.right) / 2.0, self.half_height]
+    }
+
+    // The offset of the center of the box from the position of the node
+    pub(super) fn center_offset(&self) -> f64 {
+        (self.right - self.left) / 2.0
+    }
+}
+
+/// Moves the nodes so that they and their labels don't overlap. Pinned nodes stay where
+/// they are.
+pub fn remove_overlaps(nodes: &mut [graph::Node]) {
+    let extents: Vec<Extent> = nodes.iter().map(Extent::of).collect();
+    let half_sizes: Vec<[f64; 2]> = extents.iter().map(Extent::half_size).collect();
+    // The centers of the boxes, for the nodes with positions
+    let mut centers: Vec<Option<[f64; 2]>> = std::iter::zip(&*nodes, &extents)
+        .map(|(node, extent)| {
+            let pos = node.pos.as_ref()?;
+            Some([pos.0 + extent.center_offset(), pos.1])
+        })
+        .collect();
+    let fixed: Vec<bool> = nodes.iter().map(|node| node.pinned).collect();
+
+    for _ in 0..MAX_ROUNDS {
+        let mut overlapped = false;
+        for axis in 0..2 {
+            let overlaps = overlapping_pairs(&centers, &half_sizes);
+            overlapped |= !overlaps.is_empty();
+            let constraints: Vec<(usize, usize, f64)> = overlaps
+                .into_iter()
+                .filter(|&(_a, _b, separation_axis)| separation_axis == axis)
+                .map(|(a, b, _axis)| (a, b, half_sizes[a][axis] + half_sizes[b][axis]))
+                .collect();
+            separate(&mut centers, &fixed, &constraints, axis);
+        }
+        if !overlapped {
+            break;
+        }
+    }
+    // The boxes still overlapping, e.g. in crowds where the pushes cancel each other out, are
+    // separated vertically, which is cheaper than horizontally for the wide labels
+    if !overlapping_pairs(&centers, &half_sizes).is_empty() {
+        let constraints = scan_line_constraints(&centers, &half_sizes, 1);
+        separate(&mut centers, &fixed, &constraints, 1);
+    }
+
+    for ((node, extent), center) in nodes.iter_mut().zip(&extents).zip(centers) {
+        if let (Some([x, y]), false) = (center, node.pinned) {
+            node.pos = Some(graph::Pos(x - extent.center_offset(), y));
+        }
+    }
+}
+
+// The pairs of boxes that overlap, with the axis along which they are easier to separate.
+// The first box of a pair comes first along that axis.
+fn overlapping_pairs(
+    centers: &[Option<[f64; 2]>],
+    half_sizes: &[[f64; 2]],
+) -> Vec<(usize, usize, usize)> {
+    let mut order: Vec<(usize, [f64; 2])> = centers
+        .iter()
+        .enumerate()
+        .filter_map(|(node, center)| Some((node, (*center)?)))
+        .collect();
+    let left = |&(node, center): &(usize, [f64; 2])| center[0] - half_sizes[node][0];
+    order.sort_by(|a, b| left(a).total_cmp(&left(b)));
+
+    // Sweeping from left to right, the boxes that start before the current one ends overlap
+    // with it horizontally
+    let mut pairs = Vec::new();
+    for (index, &(a, center_a)) in order.iter().enumerate() {
+        let right = center_a[0] + half_sizes[a][0];
+        for &(b, center_b) in order[index + 1..]
+            .iter()
+            .take_while(|other| left(other) < right)
+        {
+            let overlap = [0, 1].map(|axis| {
+                half_sizes[a][axis] + half_sizes[b][axis] - (center_a[axis] - center_b[axis]).abs()
+            });
+            // Boxes that were just separated may still overlap by a rounding error
+            if overlap[0] > TOLERANCE && overlap[1] > TOLERANCE {
+                let axis = if overlap[0] <= overlap[1] { 0 } else { 1 };
+                let a_first = (center_a[axis], a) < (center_b[axis], b);
+                pairs.push(if a_first { (a, b, axis) } else { (b, a, axis) });
+            }
+        }
+    }
+    pairs
+}
+
+// Constraints that keep all the boxes apart along the axis, sweeping along the other axis.
+// Each box is constrained by its neighbours among the boxes crossing the scan line, which
+// chains together all the boxes that overlap on the other axis.
+fn scan_line_constraints(
+    centers: &[Option<[f64; 2]>],
+    half_sizes: &[[f64; 2]],
+    axis: usize,
+) -> Vec<(usize, usize, f64)> {
+    let other_axis = 1 - axis;
+    // The boxes end before the others start at the same coordinate, as touching is fine
+    let mut events: Vec<(f64, bool, usize)> = Vec::new();
+    for (node, center) in centers.iter().enumerate() {
+        if let Some(center) = center {
+            let half_size = half_sizes[node][other_axis];
+            events.push((center[other_axis] - half_size, true, node));
+            events.push((center[other_axis] + half_size, false, node));
+        }
+    }
+    events.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let key = |node: usize| (centers[node].map_or(0.0, |center| center[axis]), node);
+    let mut constraints = Vec::new();
+    let mut constrain = |a: usize, b: usize| {
+        constraints.push((a, b, half_sizes[a][axis] + half_sizes[b][axis]));
+    };
+    // The boxes crossing the scan line, in order along the axis
+    let mut active: Vec<usize> = Vec::new();
+    for (_coordinate, is_start, node) in events {
+        let (coordinate, node) = key(node);
+        let index = active.partition_point(|&other| {
+            let (other_coordinate, other) = key(other);
+            other_coordinate
+                .total_cmp(&coordinate)
+                .then(other.cmp(&node))
+                .is_lt()
+        });
+        if is_start {
+            if index > 0 {
+                constrain(active[index - 1], node);
+            }
+            if index < active.len() {
+                constrain(node, active[index]);
+            }
+            active.insert(index, node);
+        } else {
+            active.remove(index);
+            if index > 0 && index < active.len() {
+                constrain(active[index - 1], active[index]);
+            }
+        }
+    }
+    constraints
+}
+
+// Moves the boxes along the axis so that the second box of each constraint is at least the
+// given distance after the first one. Pushing the boxes forwards and pushing them backwards
+// both satisfy the constraints, and so does the average of the two, which shares the
+// movement between the boxes.
+fn separate(
+    centers: &mut [Option<[f64; 2]>],
+    fixed: &[bool],
+    constraints: &[(usize, usize, f64)],
+    axis: usize,
+) {
+    if constraints.is_empty() {
+        return;
+    }
+    let mut before: Vec<Vec<(usize, f64)>> = vec![Vec::new(); centers.len()];
+    let mut after: Vec<Vec<(usize, f64)>> = vec![Vec::new(); centers.len()];
+    for &(a, b, distance) in constraints {
+        before[b].push((a, distance));
+        after[a].push((b, distance));
+    }
+    let coordinate = |node: usize| centers[node].map_or(0.0, |center| center[axis]);
+    // The constraints follow the current order, so the boxes can be pushed in that order
+    let mut order: Vec<usize> = (0..centers.len())
+        .filter(|&node| !before[node].is_empty() || !after[node].is_empty())
+        .collect();
+    order.sort_by(|&a, &b| coordinate(a).total_cmp(&coordinate(b)).then(a.cmp(&b)));
+
+    let mut forwards: Vec<f64> = (0..centers.len()).map(coordinate).collect();
+    for &node in &order {
+        if !fixed[node] {
+            for &(other, distance) in &before[node] {
+                forwards[node] = forwards[node].max(forwards[other] + distance);
+            }
+        }
+    }
+    let mut backwards: Vec<f64> = (0..centers.len()).map(coordinate).collect();
+    for &node in order.iter().rev() {
+        if !fixed[node] {
+            for &(other, distance) in &after[node] {
+                backwards[node] = backwards[node].min(backwards[other] - distance);
+            }
+        }
+    }
+    for node in order {
+        if let (Some(center), false) = (&mut centers[node], fixed[node]) {
+            center[axis] = (forwards[node] + backwards[node]) / 2.0;
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Barnes–Hut quadtree, which approximates the bodies far away by their center of mass, so
+//! that summing a force over all the bodies takes O(log n) instead of O(n) per body.
+
+/// Cells with this many bodies or fewer aren't divided further
+const LEAF_SIZE: usize = 4;
+/// Keeps coincident bodies from dividing the cells forever
+const MAX_DEPTH: usize = 32;
+
+pub struct QuadTree {
+    cells: Vec<Cell>,
+    bodies: Vec<usize>, // the body indices, with the bodies of each cell next to each other
+}
+
+struct Cell {
+    center: (f64, f64), // the center of mass
+    mass: f64,
+    corner: (f64, f64), // the minimum coordinates of the square
+    size: f64,          // the width of the square
+    bodies: std::ops::Range<usize>,
+    children: [usize; 4], // the indices of the child cells, or NO_CELL for empty quadrants
+    is_leaf: bool,
+}
+
+const NO_CELL: usize = usize::MAX;
+
+impl QuadTree {
+    pub fn new(positions: &[(f64, f64)], masses: &[f64]) -> QuadTree {
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for &(x, y) in positions {
+            (min_x, min_y) = (min_x.min(x), min_y.min(y));
+            (max_x, max_y) = (max_x.max(x), max_y.max(y));
+        }
+        let size = (max_x - min_x).max(max_y - min_y).max(f64::EPSILON);
+        let mut tree = QuadTree {
+            cells: Vec::new(),
+            bodies: (0..positions.len()).collect(),
+        };
+        if !positions.is_empty() {
+            tree.build(
+                positions,
+                masses,
+                (min_x, min_y),
+                size,
+                0..positions.len(),
+                0,
+            );
+        }
+        tree
+    }
+
+    // Adds the cell for the bodies in the range, and its children. Returns its index.
+    fn build(
+        &mut self,
+        positions: &[(f64, f64)],
+        masses: &[f64],
+        corner: (f64, f64),
+        size: f64,
+        range: std::ops::Range<usize>,
+        depth: usize,
+    ) -> usize {
+        let (mut mass, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+        for &body in &self.bodies[range.clone()] {
+            mass += masses[body];
+            sum_x += positions[body].0 * masses[body];
+            sum_y += positions[body].1 * masses[body];
+        }
+        let center = if mass > 0.0 {
+            (sum_x / mass, sum_y / mass)
+        } else {
+            (corner.0 + size / 2.0, corner.1 + size / 2.0)
+        };
+        let index = self.cells.len();
+        self.cells.push(Cell {
+            center,
+            mass,
+            corner,
+            size,
+            bodies: range.clone(),
+            children: [NO_CELL; 4],
+            is_leaf: range.len() <= LEAF_SIZE || depth >= MAX_DEPTH,
+        });
+        if self.cells[index].is_leaf {
+            return index;
+        }
+
+        let half = size / 2.0;
+        let quadrant = |body: &usize| {
+            let (x, y) = positions[*body];
+            usize::from(x >= corner.0 + half) + 2 * usize::from(y >= corner.1 + half)
+        };
+        self.bodies[range.clone()].sort_by_cached_key(quadrant);
+        let mut start = range.start;
+        for q in 0..4 {
+            let end = start
+                + self.bodies[start..range.end]
+                    .iter()
+                    .take_while(|body| quadrant(body) == q)
+                    .count();
+            if end > start {
+                let child_corner = (
+                    corner.0 + half * (q % 2) as f64,
+                    corner.1 + half * (q / 2) as f64,
+                );
+                self.cells[index].children[q] =
+                    self.build(positions, masses, child_corner, half, start..end, depth + 1);
+            }
+            start = end;
+        }
+        index
+    }
+
+    /// Calls force with the offset from the position to each of the bodies other than the
+    /// given one, and their mass. Cells that look smaller than theta from the position are
+    /// given as one body at their center of mass.
+    pub fn for_each_body(
+        &self,
+        body: usize,
+        positions: &[(f64, f64)],
+        masses: &[f64],
+        theta: f64,
+        mut force: impl FnMut(f64, f64, f64),
+    ) {
+        let (x, y) = positions[body];
+        let mut stack = if self.cells.is_empty() {
+            Vec::new()
+        } else {
+            vec![0]
+        };
+        while let Some(index) = stack.pop() {
+            let cell = &self.cells[index];
+            let (dx, dy) = (cell.center.0 - x, cell.center.1 - y);
+            let distance_squared = dx * dx + dy * dy;
+            // The cell containing the position is opened even if it looks small, so that
+            // the body doesn't count itself
+            let contains_position = (cell.corner.0..=cell.corner.0 + cell.size).contains(&x)
+                && (cell.corner.1..=cell.corner.1 + cell.size).contains(&y);
+            if !contains_position && cell.size * cell.size < theta * theta * distance_squared {
+                force(dx, dy, cell.mass);
+            } else if cell.is_leaf {
+                for &other in &self.bodies[cell.bodies.clone()] {
+                    if other != body {
+                        let (other_x, other_y) = positions[other];
+                        force(other_x - x, other_y - y, masses[other]);
+                    }
+                }
+            } else {
+                stack.extend(cell.children.iter().filter(|&&child| child != NO_CELL));
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Radial tree layout in the style of Graphviz twopi: a spanning tree of each connected
+//! component around its root, with each level of the tree on a ring of its own. The root is
+//! given with the root graph attribute, or is the node with the most neighbours.
+
+use std::cmp::Reverse;
+use std::f64::consts::TAU;
+
+use petgraph::graph::NodeIndex;
+
+use super::targets::Targets;
+use crate::graph;
+
+/// Distance between adjacent rings
+const RING_SEPARATION: f64 = 50.0;
+/// Distance between the connected components, which are placed side by side
+const COMPONENT_SEPARATION: f64 = 50.0;
+
+/// Tree levels on rings around a root, as the targets of a TargetLayout
+pub struct Radial;
+
+impl Targets for Radial {
+    fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+        positions(g)
+    }
+}
+
+/// The positions of the nodes in the order of their indices
+pub fn positions(g: &graph::Graph) -> Vec<graph::Pos> {
+    let node_count = g.graph.node_count();
+    let neighbours: Vec<Vec<usize>> = (0..node_count)
+        .map(|node| {
+            g.graph
+                .neighbors_undirected(NodeIndex::new(node))
+                .map(|neighbour| neighbour.index())
+                .collect()
+        })
+        .collect();
+    let requested_root = g
+        .attrs()
+        .get("root")
+        .and_then(|id| g.resolve_node_index(&graph::NodeId::from(id.clone())).ok())
+        .map(|node_index| node_index.index());
+
+    // The requested root goes first, and the rest in the order of their neighbour counts
+    let mut roots: Vec<usize> = (0..node_count).collect();
+    roots.sort_by_key(|&node| {
+        (
+            Some(node) != requested_root,
+            Reverse(neighbours[node].len()),
+        )
+    });
+
+    let mut positions = vec![graph::Pos(0.0, 0.0); node_count];
+    let mut placed = vec![false; node_count];
+    let mut offset = 0.0;
+    for root in roots {
+        if placed[root] {
+            continue;
+        }
+        let (component, radius) = tree_positions(root, &neighbours, &mut placed);
+        for (node, graph::Pos(x, y)) in component {
+            positions[node] = graph::Pos(offset + radius + x, y);
+        }
+        offset += 2.0 * radius + COMPONENT_SEPARATION;
+    }
+    positions
+}
+
+/// Lays out the breadth-first spanning tree from the root, giving each subtree a share of
+/// the angle of its parent by the number of leaves in it. Returns the positions of the nodes
+/// relative to the root, and the radius of the outermost ring.
+fn tree_positions(
+    root: usize,
+    neighbours: &[Vec<usize>],
+    placed: &mut [bool],
+) -> (Vec<(usize, graph::Pos)>, f64) {
+    // The nodes in the breadth-first order, with the children and depths by that order
+    let mut order = vec![root];
+    let mut children: Vec<Vec<usize>> = vec![Vec::new()];
+    let mut depths = vec![0usize];
+    placed[root] = true;
+    let mut index = 0;
+    while index < order.len() {
+        for &neighbour in &neighbours[order[index]] {
+            if !placed[neighbour] {
+                placed[neighbour] = true;
+                children[index].push(order.len());
+                order.push(neighbour);
+                children.push(Vec::new());
+                depths.push(depths[index] + 1);
+            }
+        }
+        index += 1;
+    }
+
+    let mut leaves = vec![1.0f64; order.len()];
+    for index in (0..order.len()).rev() {
+        if !children[index].is_empty() {
+            leaves[index] = children[index].iter().map(|&child| leaves[child]).sum();
+        }
+    }
+
+    let mut wedges = vec![(0.0f64, TAU); order.len()]; // (start angle, size)
+    for index in 0..order.len() {
+        let (mut start, size) = wedges[index];
+        for &child in &children[index] {
+            let child_size = size * leaves[child] / leaves[index];
+            wedges[child] = (start, child_size);
+            start += child_size;
+        }
+    }
+
+    let positions = order
+        .iter()
+        .enumerate()
+        .map(|(index, &node)| {
+            let radius = depths[index] as f64 * RING_SEPARATION;
+            let (start, size) = wedges[index];
+            let angle = start + size / 2.0;
+            (node, graph::Pos(radius * angle.cos(), radius * angle.sin()))
+        })
+        .collect();
+    let max_depth = depths.iter().max().copied().unwrap_or(0);
+    (positions, max_depth as f64 * RING_SEPARATION)
+}
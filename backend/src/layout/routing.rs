@@ -0,0 +1,299 @@
+//! Edge routing, which runs once the nodes have stopped moving. Edges that would cross other
+//! nodes or their labels detour around them, parallel edges between the same nodes are
+//! spread apart, and self-loops get loops of increasing size. The routes are smoothed into
+//! cubic Bézier curves.
+
+use std::collections::HashMap;
+
+use super::overlap::Extent;
+use super::NodesEdges;
+use crate::graph;
+
+/// The space left between an edge and the nodes it goes around
+const CLEARANCE: f64 = 4.0;
+/// How many times a route may be split to go around the nodes in its way
+const MAX_DETOUR_DEPTH: usize = 3;
+/// The distance between parallel edges
+const EDGE_SPACING: f64 = 8.0;
+/// The height of the smallest self-loop
+const LOOP_SIZE: f64 = 20.0;
+
+type Point = [f64; 2];
+
+// The box taken by a node and its label
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min: Point,
+    max: Point,
+}
+
+impl Bounds {
+    fn center(&self) -> Point {
+        [0, 1].map(|axis| (self.min[axis] + self.max[axis]) / 2.0)
+    }
+
+    // Where along the segment it enters the box, from 0 to 1, or None if it misses the box
+    fn entry(&self, from: Point, to: Point) -> Option<f64> {
+        let (mut enter, mut exit) = (0.0f64, 1.0f64);
+        for axis in 0..2 {
+            let delta = to[axis] - from[axis];
+            if delta.abs() < f64::EPSILON {
+                if !(self.min[axis]..=self.max[axis]).contains(&from[axis]) {
+                    return None;
+                }
+                continue;
+            }
+            let a = (self.min[axis] - from[axis]) / delta;
+            let b = (self.max[axis] - from[axis]) / delta;
+            enter = enter.max(a.min(b));
+            exit = exit.min(a.max(b));
+            if enter > exit {
+                return None;
+            }
+        }
+        Some(enter)
+    }
+}
+
+// The boxes of the nodes, in a uniform grid for finding the ones near an edge
+struct Obstacles {
+    boxes: Vec<Option<Bounds>>,
+    cells: HashMap<(i64, i64), Vec<usize>>,
+    cell_size: f64,
+}
+
+impl Obstacles {
+    fn new(nodes: &[graph::Node]) -> Obstacles {
+        let boxes: Vec<Option<Bounds>> = nodes
+            .iter()
+            .map(|node| {
+                let pos = node.pos.as_ref()?;
+                let extent = Extent::of(node);
+                let [half_width, half_height] = extent.half_size();
+                let center = [pos.0 + extent.center_offset(), pos.1];
+                Some(Bounds {
+                    min: [center[0] - half_width, center[1] - half_height],
+                    max: [center[0] + half_width, center[1] + half_height],
+                })
+            })
+            .collect();
+        // Cells about the size of the boxes keep both the cells per box and the boxes per
+        // cell low
+        let sizes: Vec<f64> = boxes
+            .iter()
+            .flatten()
+            .map(|bounds| (bounds.max[0] - bounds.min[0]).max(bounds.max[1] - bounds.min[1]))
+            .collect();
+        let cell_size = (sizes.iter().sum::<f64>() / sizes.len().max(1) as f64).max(1.0);
+
+        let mut obstacles = Obstacles {
+            boxes,
+            cells: HashMap::new(),
+            cell_size,
+        };
+        for (node, bounds) in obstacles.boxes.iter().enumerate() {
+            let Some(bounds) = bounds else {
+                continue;
+            };
+            let min = obstacles.cell_of(bounds.min);
+            let max = obstacles.cell_of(bounds.max);
+            for x in min.0..=max.0 {
+                for y in min.1..=max.1 {
+                    obstacles.cells.entry((x, y)).or_default().push(node);
+                }
+            }
+        }
+        obstacles
+    }
+
+    fn cell_of(&self, point: Point) -> (i64, i64) {
+        (
+            (point[0] / self.cell_size).floor() as i64,
+            (point[1] / self.cell_size).floor() as i64,
+        )
+    }
+
+    // The nodes in the cells the segment goes through, walking from cell to cell
+    fn near_segment(&self, from: Point, to: Point) -> Vec<usize> {
+        let start = self.cell_of(from);
+        let end = self.cell_of(to);
+        let mut cell = [start.0, start.1];
+        let mut step = [0i64; 2];
+        // How far along the segment the next cell boundary is on each axis, and how far apart
+        // the boundaries are
+        let mut next = [f64::INFINITY; 2];
+        let mut spacing = [f64::INFINITY; 2];
+        for axis in 0..2 {
+            let delta = to[axis] - from[axis];
+            if delta.abs() >= f64::EPSILON {
+                step[axis] = if delta > 0.0 { 1 } else { -1 };
+                let boundary = (cell[axis] + i64::from(delta > 0.0)) as f64 * self.cell_size;
+                next[axis] = (boundary - from[axis]) / delta;
+                spacing[axis] = self.cell_size / delta.abs();
+            }
+        }
+
+        let mut nodes = Vec::new();
+        let cell_count = (end.0 - start.0).abs() + (end.1 - start.1).abs() + 1;
+        for _ in 0..cell_count {
+            if let Some(cell_nodes) = self.cells.get(&(cell[0], cell[1])) {
+                nodes.extend(cell_nodes);
+            }
+            let axis = if next[0] < next[1] { 0 } else { 1 };
+            cell[axis] += step[axis];
+            next[axis] += spacing[axis];
+        }
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+
+    // The first box the segment enters, other than the ones of the given nodes
+    fn first_crossed(&self, from: Point, to: Point, ends: [usize; 2]) -> Option<usize> {
+        self.near_segment(from, to)
+            .into_iter()
+            .filter(|node| !ends.contains(node))
+            .filter_map(|node| Some((self.boxes[node]?.entry(from, to)?, node)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_entry, node)| node)
+    }
+
+    // The points to go through between the two points, so that the route doesn't cross the
+    // nodes in between. Each box in the way is passed on the side its center is farther from.
+    fn detour(&self, from: Point, to: Point, ends: [usize; 2], depth: usize) -> Vec<Point> {
+        if depth == 0 {
+            return Vec::new();
+        }
+        let Some(node) = self.first_crossed(from, to, ends) else {
+            return Vec::new();
+        };
+        let Some(bounds) = self.boxes[node] else {
+            return Vec::new();
+        };
+        let length = (to[0] - from[0]).hypot(to[1] - from[1]);
+        if length < f64::EPSILON {
+            return Vec::new();
+        }
+        let normal = [(from[1] - to[1]) / length, (to[0] - from[0]) / length];
+        let center = bounds.center();
+        let side = (center[0] - from[0]) * normal[0] + (center[1] - from[1]) * normal[1];
+        let direction = if side > 0.0 { -1.0 } else { 1.0 };
+        // How far the box reaches from its center along the normal
+        let reach = (bounds.max[0] - bounds.min[0]) / 2.0 * normal[0].abs()
+            + (bounds.max[1] - bounds.min[1]) / 2.0 * normal[1].abs()
+            + CLEARANCE;
+        let waypoint = [0, 1].map(|axis| center[axis] + direction * reach * normal[axis]);
+
+        let mut points = self.detour(from, waypoint, ends, depth - 1);
+        points.push(waypoint);
+        points.extend(self.detour(waypoint, to, ends, depth - 1));
+        points
+    }
+}
+
+/// Routes the edges around the nodes. Edges that can be drawn as straight lines are left
+/// without a route.
+pub fn route_edges(nodes_edges: &mut NodesEdges) {
+    let nodes = &nodes_edges.nodes;
+    let indices: HashMap<&graph::NodeId, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (&node.id, index))
+        .collect();
+    let obstacles = Obstacles::new(nodes);
+
+    // The edges between the same nodes, in either direction, are spread apart
+    let mut parallel: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for (edge_index, (a, b, _edge)) in nodes_edges.edges.iter().enumerate() {
+        if let (Some(&a), Some(&b)) = (indices.get(a), indices.get(b)) {
+            parallel
+                .entry((a.min(b), a.max(b)))
+                .or_default()
+                .push(edge_index);
+        }
+    }
+
+    let position = |node: usize| nodes[node].pos.as_ref().map(|pos| [pos.0, pos.1]);
+    for ((a, b), edge_indices) in parallel {
+        let (Some(pos_a), Some(pos_b)) = (position(a), position(b)) else {
+            continue;
+        };
+        let count = edge_indices.len();
+        for (index, edge_index) in edge_indices.into_iter().enumerate() {
+            let (source, _target, edge) = &mut nodes_edges.edges[edge_index];
+            edge.route = if a == b {
+                self_loop(pos_a, index)
+            } else {
+                let offset = (index as f64 - (count - 1) as f64 / 2.0) * EDGE_SPACING;
+                let route = route(&obstacles, [a, b], [pos_a, pos_b], offset);
+                // The route runs from the source to the target
+                if indices[&*source] == a {
+                    route
+                } else {
+                    route.into_iter().rev().collect()
+                }
+            };
+        }
+    }
+}
+
+// The route from the first node to the second, shifted sideways by the offset. Empty when a
+// straight line will do.
+fn route(
+    obstacles: &Obstacles,
+    ends: [usize; 2],
+    [from, to]: [Point; 2],
+    offset: f64,
+) -> Vec<graph::Pos> {
+    let length = (to[0] - from[0]).hypot(to[1] - from[1]);
+    if length < f64::EPSILON {
+        return Vec::new();
+    }
+    let mut waypoints = obstacles.detour(from, to, ends, MAX_DETOUR_DEPTH);
+    if waypoints.is_empty() && offset == 0.0 {
+        return Vec::new();
+    }
+    if waypoints.is_empty() {
+        waypoints.push([(from[0] + to[0]) / 2.0, (from[1] + to[1]) / 2.0]);
+    }
+    let normal = [(from[1] - to[1]) / length, (to[0] - from[0]) / length];
+    for waypoint in &mut waypoints {
+        waypoint[0] += offset * normal[0];
+        waypoint[1] += offset * normal[1];
+    }
+
+    let mut points = vec![from];
+    points.extend(waypoints);
+    points.push(to);
+    smooth(&points)
+}
+
+// A loop from the node back to itself, above it, as the y axis points up. Each further loop
+// is bigger, so that they nest.
+fn self_loop([x, y]: Point, index: usize) -> Vec<graph::Pos> {
+    let size = LOOP_SIZE + index as f64 * EDGE_SPACING;
+    // The curve reaches 3/4 of the way to its control points
+    let height = size * 4.0 / 3.0;
+    vec![
+        graph::Pos(x, y),
+        graph::Pos(x - size / 2.0, y + height),
+        graph::Pos(x + size / 2.0, y + height),
+        graph::Pos(x, y),
+    ]
+}
+
+// The control points of a Catmull-Rom spline through the points, as cubic Bézier curves
+fn smooth(points: &[Point]) -> Vec<graph::Pos> {
+    let mut route = vec![graph::Pos(points[0][0], points[0][1])];
+    for index in 0..points.len() - 1 {
+        let previous = points[index.saturating_sub(1)];
+        let [start, end] = [points[index], points[index + 1]];
+        let next = points[(index + 2).min(points.len() - 1)];
+        let first = [0, 1].map(|axis| start[axis] + (end[axis] - previous[axis]) / 6.0);
+        let second = [0, 1].map(|axis| end[axis] - (next[axis] - start[axis]) / 6.0);
+        route.push(graph::Pos(first[0], first[1]));
+        route.push(graph::Pos(second[0], second[1]));
+        route.push(graph::Pos(end[0], end[1]));
+    }
+    route
+}
@@ -0,0 +1,96 @@
+use std::marker::PhantomData;
+
+use rand::rngs::StdRng;
+
+use super::{resolve_edges, LayoutEngine, LayoutParams, NodesEdges, Result};
+use crate::graph;
+
+/// The portion of the remaining distance the nodes move towards their targets on each step
+const STEP_FRACTION: f64 = 0.3;
+/// Nodes closer than this to their targets are considered to have arrived
+const ARRIVAL_DISTANCE: f64 = 0.5;
+
+/// How the targets of a TargetLayout are computed
+pub trait Targets: Send + 'static {
+    /// The targets in the order of the node indices
+    fn positions(g: &graph::Graph) -> Vec<graph::Pos>;
+}
+
+/// A layout whose positions are computed up front. The steps move the nodes from where they
+/// were towards their targets, so that switching layouts is easy to follow.
+pub struct TargetLayout<T: Targets> {
+    nodes: Vec<graph::Node>,
+    edges: Vec<(graph::NodeId, graph::NodeId, graph::Edge)>,
+    targets: Vec<graph::Pos>,
+    kind: PhantomData<T>,
+}
+
+impl<T: Targets> LayoutEngine for TargetLayout<T> {
+    fn init(
+        g: &graph::Graph,
+        _params: &LayoutParams,
+        _incremental: bool,
+        _rng: &mut StdRng,
+    ) -> Result<Self> {
+        let mut targets = T::positions(g);
+        let nodes: Vec<graph::Node> = g.graph.node_weights().cloned().collect();
+
+        // Keep the graph where it was, so that it doesn't slide across the view
+        let positioned: Vec<(&graph::Pos, &graph::Pos)> = nodes
+            .iter()
+            .zip(&targets)
+            .filter_map(|(node, target)| Some((node.pos.as_ref()?, target)))
+            .collect();
+        if !positioned.is_empty() {
+            let count = positioned.len() as f64;
+            let dx = positioned
+                .iter()
+                .map(|(pos, target)| pos.0 - target.0)
+                .sum::<f64>()
+                / count;
+            let dy = positioned
+                .iter()
+                .map(|(pos, target)| pos.1 - target.1)
+                .sum::<f64>()
+                / count;
+            for target in &mut targets {
+                target.0 += dx;
+                target.1 += dy;
+            }
+        }
+
+        Ok(TargetLayout {
+            nodes,
+            edges: resolve_edges(g)?,
+            targets,
+            kind: PhantomData,
+        })
+    }
+
+    fn step(&mut self) -> (NodesEdges, bool) {
+        let mut is_finished = true;
+        for (node, target) in self.nodes.iter_mut().zip(&self.targets) {
+            if node.pinned && node.pos.is_some() {
+                continue;
+            }
+            let pos = match &node.pos {
+                Some(graph::Pos(x, y)) => {
+                    let (dx, dy) = (target.0 - x, target.1 - y);
+                    if dx.hypot(dy) > ARRIVAL_DISTANCE {
+                        is_finished = false;
+                        graph::Pos(x + dx * STEP_FRACTION, y + dy * STEP_FRACTION)
+                    } else {
+                        target.clone()
+                    }
+                }
+                None => target.clone(),
+            };
+            node.pos = Some(pos);
+        }
+        let nodes_edges = NodesEdges {
+            nodes: self.nodes.clone(),
+            edges: self.edges.clone(),
+        };
+        (nodes_edges, is_finished)
+    }
+}
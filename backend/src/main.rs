@@ -4,19 +4,21 @@ mod assets;
 mod bg_layout;
 mod graph;
 mod graph_data;
+mod graph_delta;
+mod graphs;
 mod layout;
 mod server;
+mod state;
 
 use clap::Parser;
 use env_logger::Env;
 use std::io::{Read, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::bg_layout::BgLayout;
-use crate::graph::Graph;
-use crate::graph_data::GraphData;
+use crate::graphs::{Graphs, DEFAULT_GRAPH};
+use crate::layout::EngineKind;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -35,6 +37,12 @@ pub enum Error {
         source: server::Error,
     },
 
+    #[error("State error: {source}")]
+    StateError {
+        #[from]
+        source: state::Error,
+    },
+
     #[error("Layout error: {source}")]
     LocalIpAddressError {
         #[from]
@@ -53,6 +61,24 @@ struct Args {
 
     #[arg(long, default_value_t = false)]
     sh: bool,
+
+    /// Layout engine for the graphs that don't select one themselves
+    #[arg(long, value_enum, default_value_t = EngineKind::Force)]
+    layout: EngineKind,
+
+    /// Seed of the random numbers of the layouts, for the graphs that don't select one
+    /// themselves. The same graph with the same seed is always laid out the same way.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// File the graphs are saved to, and loaded from when starting, so that they survive
+    /// restarts
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Seconds between saving the graphs to the state file
+    #[arg(long, default_value_t = 10)]
+    state_interval: u64,
 }
 
 // Function to handle the listening address logic
@@ -134,23 +160,27 @@ async fn tokio_main(
     verbose: bool,
     mut for_sh_pipe: Option<std::io::PipeWriter>,
 ) -> Result<()> {
-    let graph = Graph::new();
-    let graph_data = Arc::new(Mutex::new(GraphData {
-        graph,
-        layout: None,
-    }));
-    let data = graph_data.clone();
-
-    let bg_layout = BgLayout::new(graph_data.clone());
-    let bg_control = bg_layout.start();
+    let graphs = Graphs::new(args.layout, args.seed);
+    if let Some(state_file) = &args.state_file {
+        // A state file that can't be loaded isn't overwritten
+        state::load(state_file, &graphs).await?;
+        let graphs = graphs.clone();
+        let state_file = state_file.clone();
+        let interval = Duration::from_secs(args.state_interval.max(1));
+        tokio::spawn(async move {
+            state::save_periodically(state_file, &graphs, interval).await;
+        });
+    }
+    graphs.get_or_create(DEFAULT_GRAPH).await;
 
     let listen_addr = get_listen_address(args.listen)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
 
     let (addresses_tx, addresses_rx) = tokio::sync::oneshot::channel();
 
+    let server_graphs = graphs.clone();
     let join = tokio::spawn(async move {
-        match server::run_server(listen_addr, data, bg_control, addresses_tx).await {
+        match server::run_server(listen_addr, server_graphs, addresses_tx).await {
             Ok(x) => x.await.map_err(|err| Error::from(err)),
             Err(err) => Err(Error::from(err)),
         }
@@ -200,6 +230,11 @@ async fn tokio_main(
     // TODO: ..but we could maybe do it better somehow? E.g. try join before drop, to extract fast errors?
     join.await.unwrap().unwrap();
 
+    // The latest changes are saved when the server stops
+    if let Some(state_file) = &args.state_file {
+        state::save(state_file, &graphs).await?;
+    }
+
     Ok(())
 }
 
@@ -1,21 +1,22 @@
 use actix_web::{
     middleware::Logger,
     web::{self, Data},
-    App, HttpServer, Responder,
+    App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder,
 };
+use futures_util::StreamExt; // For stream combinators like .next()
 use serde::{Deserialize, Serialize};
 use std::{backtrace::Backtrace, time::Duration};
 use std::{convert::Infallible, net::SocketAddr};
-use tokio_stream::wrappers::BroadcastStream;
-use tokio_stream::StreamExt; // For stream combinators like .next()
 
 use std::sync::Arc;
 
+use crate::bg_layout::{Update, UpdateStats};
+use crate::graphs::{GraphEntry, GraphInfo, GraphsType, DEFAULT_GRAPH};
+use crate::layout::{EngineKind, LayoutParams};
 use crate::{
     assets,
-    graph::{EdgeId, GraphResponse, Node, NodeId},
+    graph::{Attributes, EdgeId, Graph, GraphResponse, Node, NodeId, Pos},
 };
-use crate::{bg_layout, graph_data::GraphDataType};
 
 #[allow(clippy::enum_variant_names)]
 #[derive(thiserror::Error, Debug)]
@@ -46,6 +47,9 @@ pub enum Error {
         #[from]
         source: std::io::Error,
     },
+
+    #[error("Graph not found: {name}")]
+    GraphNotFound { name: String },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -57,6 +61,7 @@ impl Error {
             Error::GraphError { backtrace, .. } => Some(backtrace),
             Error::LayoutError { backtrace, .. } => Some(backtrace),
             Error::IOError { .. } => None,
+            Error::GraphNotFound { .. } => None,
         }
     }
 }
@@ -69,7 +74,35 @@ impl actix_web::ResponseError for Error {
     }
 
     fn status_code(&self) -> actix_web::http::StatusCode {
-        actix_web::http::StatusCode::from_u16(400u16).unwrap()
+        match self {
+            Error::GraphNotFound { .. } => actix_web::http::StatusCode::NOT_FOUND,
+            _ => actix_web::http::StatusCode::from_u16(400u16).unwrap(),
+        }
+    }
+}
+
+/// The name of the graph a request is about. The routes outside /g/{name} are about the
+/// default graph.
+struct GraphName(String);
+
+impl FromRequest for GraphName {
+    type Error = actix_web::Error;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let name = req.match_info().get("name").unwrap_or(DEFAULT_GRAPH);
+        std::future::ready(Ok(GraphName(name.to_string())))
+    }
+}
+
+impl GraphName {
+    async fn get(&self, graphs: &GraphsType) -> Result<GraphEntry> {
+        graphs
+            .get(&self.0)
+            .await
+            .ok_or_else(|| Error::GraphNotFound {
+                name: self.0.clone(),
+            })
     }
 }
 
@@ -78,6 +111,30 @@ struct EdgeRequest {
     a: NodeId,
     b: NodeId,
     id: Option<EdgeId>,
+    #[serde(default)]
+    attrs: Attributes,
+    // Shorthands for the attributes of the same names
+    weight: Option<f64>,
+    len: Option<f64>,
+    minlen: Option<f64>,
+}
+
+impl EdgeRequest {
+    fn add_to(self, graph: &mut Graph) -> crate::graph::Result<()> {
+        let mut attrs = self.attrs;
+        for (name, value) in [
+            ("weight", self.weight),
+            ("len", self.len),
+            ("minlen", self.minlen),
+        ] {
+            if let Some(value) = value {
+                attrs.insert(name.to_string(), value.to_string());
+            }
+        }
+        graph.ensure_node(&self.a);
+        graph.ensure_node(&self.b);
+        graph.add_edge(self.a, self.b, self.id, attrs)
+    }
 }
 
 fn no_nodes() -> Vec<Node> {
@@ -88,6 +145,15 @@ fn no_edge_requests() -> Vec<EdgeRequest> {
     Vec::new()
 }
 
+// Tells a null apart from a missing field, which stays None through serde(default)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct AddRequest {
     #[serde(default = "no_nodes")]
@@ -96,37 +162,173 @@ struct AddRequest {
     edges: Vec<EdgeRequest>,
 }
 
+// Struct instead of a tuple, because the path may also contain the graph name
+#[derive(Deserialize, Debug, Clone)]
+struct IdPath {
+    id: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum GraphvizMode {
+    /// Add the nodes and edges to the existing graph
+    #[default]
+    Add,
+    /// Replace the existing graph, keeping the positions of the nodes that remain
+    Replace,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct GraphvizQuery {
+    #[serde(default)]
+    mode: GraphvizMode,
+}
+
+/// When posted, only the fields given are changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LayoutSettings {
+    /// When posted, null selects the engine by the layout attribute of the graph
+    #[serde(default, deserialize_with = "present")]
+    engine: Option<Option<EngineKind>>,
+    /// When posted, null selects the seed given with --seed
+    #[serde(default, deserialize_with = "present")]
+    seed: Option<Option<u64>>,
+}
+
+/// The edits the clients can make over the WebSocket
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientCommand {
+    MoveNode { id: NodeId, pos: Pos },
+    PinNode { id: NodeId },
+    UnpinNode { id: NodeId },
+    DeleteNode { id: NodeId },
+    AddEdge(EdgeRequest),
+}
+
+impl ClientCommand {
+    async fn apply(self, entry: &GraphEntry) -> Result<()> {
+        let mut data = entry.data.lock().await;
+        match self {
+            ClientCommand::MoveNode { id, pos } => data.graph.move_node(&id, pos)?,
+            ClientCommand::PinNode { id } => data.graph.set_pinned(&id, true)?,
+            ClientCommand::UnpinNode { id } => data.graph.set_pinned(&id, false)?,
+            ClientCommand::DeleteNode { id } => {
+                data.graph.remove_node(&id)?;
+            }
+            ClientCommand::AddEdge(edge) => edge.add_to(&mut data.graph)?,
+        }
+        data.reset_layout();
+        Ok(())
+    }
+}
+
+#[actix_web::get("/graphs")]
+async fn list_graphs(graphs: Data<GraphsType>) -> web::Json<Vec<GraphInfo>> {
+    web::Json(graphs.list().await)
+}
+
 #[actix_web::get("/graph")]
-async fn list(data: Data<GraphDataType>) -> actix_web::Result<web::Json<GraphResponse>, Error> {
-    let data = data.lock().await;
+async fn list(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+) -> actix_web::Result<web::Json<GraphResponse>, Error> {
+    let entry = name.get(&graphs).await?;
+    let data = entry.data.lock().await;
     let nodes_edges = data.graph.graph_response();
     Ok(web::Json(nodes_edges))
 }
 
 #[actix_web::post("/graph")]
 async fn add(
-    data: Data<GraphDataType>,
+    graphs: Data<GraphsType>,
+    name: GraphName,
     request: web::Json<AddRequest>,
 ) -> actix_web::Result<web::Json<Option<String>>, Error> {
-    let mut data = data.lock().await;
+    let entry = graphs.get_or_create(&name.0).await;
+    let mut data = entry.data.lock().await;
     data.reset_layout();
     let request = request.into_inner();
     for node in request.nodes {
         data.graph.add_node(node)
     }
     for edge in request.edges {
-        data.graph.ensure_node(&edge.a);
-        data.graph.ensure_node(&edge.b);
-        data.graph.add_edge(edge.a, edge.b, edge.id)?
+        edge.add_to(&mut data.graph)?
     }
     Ok(web::Json(None::<String>))
 }
 
+#[actix_web::delete("/graph/nodes/{id}")]
+async fn delete_node(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    path: web::Path<IdPath>,
+) -> actix_web::Result<web::Json<Option<String>>, Error> {
+    let entry = name.get(&graphs).await?;
+    let mut data = entry.data.lock().await;
+    data.graph
+        .remove_node(&NodeId::from(path.into_inner().id))?;
+    data.reset_layout();
+    Ok(web::Json(None::<String>))
+}
+
+#[actix_web::post("/graph/nodes/{id}/pin")]
+async fn pin_node(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    path: web::Path<IdPath>,
+) -> actix_web::Result<web::Json<Option<String>>, Error> {
+    let entry = name.get(&graphs).await?;
+    let mut data = entry.data.lock().await;
+    data.graph
+        .set_pinned(&NodeId::from(path.into_inner().id), true)?;
+    data.reset_layout();
+    Ok(web::Json(None::<String>))
+}
+
+#[actix_web::delete("/graph/nodes/{id}/pin")]
+async fn unpin_node(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    path: web::Path<IdPath>,
+) -> actix_web::Result<web::Json<Option<String>>, Error> {
+    let entry = name.get(&graphs).await?;
+    let mut data = entry.data.lock().await;
+    data.graph
+        .set_pinned(&NodeId::from(path.into_inner().id), false)?;
+    data.reset_layout();
+    Ok(web::Json(None::<String>))
+}
+
+#[actix_web::delete("/graph/edges/{id}")]
+async fn delete_edge(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    path: web::Path<IdPath>,
+) -> actix_web::Result<web::Json<Option<String>>, Error> {
+    let entry = name.get(&graphs).await?;
+    let mut data = entry.data.lock().await;
+    data.graph
+        .remove_edge(&EdgeId::from(path.into_inner().id))?;
+    data.reset_layout();
+    Ok(web::Json(None::<String>))
+}
+
 #[actix_web::post("/graphviz")]
-async fn post_graphviz(data: Data<GraphDataType>, body: String) -> actix_web::Result<String> {
-    let mut data = data.lock().await;
+async fn post_graphviz(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    query: web::Query<GraphvizQuery>,
+    body: String,
+) -> actix_web::Result<String> {
+    let entry = graphs.get_or_create(&name.0).await;
+    let mut data = entry.data.lock().await;
     data.reset_layout();
-    match data.graph.parse_graphviz(&body) {
+    let result = match query.mode {
+        GraphvizMode::Add => data.graph.parse_graphviz(&body),
+        GraphvizMode::Replace => data.graph.replace_graphviz(&body),
+    };
+    match result {
         Ok(()) => Ok(String::new()),
         Err(error) => Err(actix_web::error::ErrorBadRequest(format!(
             "Parse error: {error:?}",
@@ -134,38 +336,206 @@ async fn post_graphviz(data: Data<GraphDataType>, body: String) -> actix_web::Re
     }
 }
 
+#[actix_web::get("/graphviz")]
+async fn get_graphviz(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+) -> actix_web::Result<HttpResponse, Error> {
+    let entry = name.get(&graphs).await?;
+    let data = entry.data.lock().await;
+    Ok(HttpResponse::Ok()
+        .content_type("text/vnd.graphviz")
+        .body(data.graph.to_graphviz()))
+}
+
+#[actix_web::get("/layout")]
+async fn get_layout(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+) -> actix_web::Result<web::Json<LayoutSettings>, Error> {
+    let entry = name.get(&graphs).await?;
+    let data = entry.data.lock().await;
+    Ok(web::Json(LayoutSettings {
+        engine: Some(Some(data.engine_kind())),
+        seed: Some(Some(data.seed())),
+    }))
+}
+
+#[actix_web::post("/layout")]
+async fn post_layout(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    request: web::Json<LayoutSettings>,
+) -> actix_web::Result<web::Json<Option<String>>, Error> {
+    let entry = name.get(&graphs).await?;
+    let mut data = entry.data.lock().await;
+    let settings = request.into_inner();
+    if let Some(engine) = settings.engine {
+        data.engine = engine;
+    }
+    if let Some(seed) = settings.seed {
+        data.seed = seed;
+    }
+    // The new engine starts from the current positions
+    data.restart_layout();
+    Ok(web::Json(None::<String>))
+}
+
+#[actix_web::get("/layout/params")]
+async fn get_layout_params(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+) -> actix_web::Result<web::Json<LayoutParams>, Error> {
+    let entry = name.get(&graphs).await?;
+    let data = entry.data.lock().await;
+    Ok(web::Json(data.params.clone()))
+}
+
+#[actix_web::put("/layout/params")]
+async fn put_layout_params(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    request: web::Json<LayoutParams>,
+) -> actix_web::Result<web::Json<Option<String>>, Error> {
+    let params = request.into_inner();
+    params.validate()?;
+    let entry = name.get(&graphs).await?;
+    let mut data = entry.data.lock().await;
+    data.params = params;
+    // The simulation restarts from the current positions
+    data.restart_layout();
+    Ok(web::Json(None::<String>))
+}
+
 #[actix_web::get("/stream")]
-async fn from_channel(bg_control: web::Data<bg_layout::BgControl>) -> impl Responder {
-    let updates = BroadcastStream::new(bg_control.updates());
+async fn from_channel(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+) -> actix_web::Result<impl Responder, Error> {
+    let entry = name.get(&graphs).await?;
+    // Reconnecting clients get a new snapshot as well
+    let events = entry
+        .bg_control
+        .subscribe()
+        .await
+        .map(|update| Ok::<_, Infallible>(update_event(&update)));
 
-    let events = updates.map(|update| {
-        let update = update.expect("woot, there should have been an update..");
-        let json_data = serde_json::to_string(&update).expect("Failed to encode Update to JSON");
-        Ok::<_, Infallible>(actix_web_lab::sse::Event::Data(
-            actix_web_lab::sse::Data::new(json_data),
-        ))
-    });
+    Ok(actix_web_lab::sse::Sse::from_stream(events).with_keep_alive(Duration::from_secs(5)))
+}
+
+#[actix_web::get("/ws")]
+async fn websocket(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+    req: HttpRequest,
+    body: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let entry = name.get(&graphs).await?;
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(websocket_session(entry, session, messages));
+    Ok(response)
+}
+
+// Sends the same updates as /stream, and applies the commands from the client. The results
+// of the commands reach all the clients via the updates.
+async fn websocket_session(
+    entry: GraphEntry,
+    mut session: actix_ws::Session,
+    mut messages: actix_ws::MessageStream,
+) {
+    let updates = entry.bg_control.subscribe().await;
+    let mut updates = std::pin::pin!(updates);
+    loop {
+        let sent = tokio::select! {
+            update = updates.next() => match update {
+                Some(update) => {
+                    let json_data =
+                        serde_json::to_string(&update).expect("Failed to encode Update to JSON");
+                    session.text(json_data).await
+                }
+                None => break,
+            },
+            message = messages.recv() => match message {
+                Some(Ok(actix_ws::Message::Text(text))) => match run_command(&entry, &text).await {
+                    Ok(()) => Ok(()),
+                    Err(message) => {
+                        let json_data = serde_json::json!({"type": "error", "message": message});
+                        session.text(json_data.to_string()).await
+                    }
+                },
+                Some(Ok(actix_ws::Message::Ping(bytes))) => session.pong(&bytes).await,
+                Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => Ok(()),
+            },
+        };
+        if sent.is_err() {
+            // The client is gone
+            return;
+        }
+    }
+    let _ = session.close(None).await;
+}
+
+// The error is sent back to the client
+async fn run_command(entry: &GraphEntry, text: &str) -> Result<(), String> {
+    let command: ClientCommand =
+        serde_json::from_str(text).map_err(|error| format!("Invalid command: {error}"))?;
+    command
+        .apply(entry)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+#[actix_web::get("/stats")]
+async fn stats(
+    graphs: Data<GraphsType>,
+    name: GraphName,
+) -> actix_web::Result<web::Json<UpdateStats>, Error> {
+    let entry = name.get(&graphs).await?;
+    Ok(web::Json(entry.bg_control.stats()))
+}
+
+fn update_event(update: &Update) -> actix_web_lab::sse::Event {
+    let json_data = serde_json::to_string(update).expect("Failed to encode Update to JSON");
+    actix_web_lab::sse::Event::Data(
+        actix_web_lab::sse::Data::new(json_data).id(update.serial().to_string()),
+    )
+}
 
-    actix_web_lab::sse::Sse::from_stream(events).with_keep_alive(Duration::from_secs(5))
+// The routes of a single graph. These are served under /g/{name}, and for the default graph
+// also at the top level.
+fn graph_services(cfg: &mut web::ServiceConfig) {
+    cfg.service(list)
+        .service(add)
+        .service(delete_node)
+        .service(pin_node)
+        .service(unpin_node)
+        .service(delete_edge)
+        .service(post_graphviz)
+        .service(get_graphviz)
+        .service(get_layout)
+        .service(post_layout)
+        .service(get_layout_params)
+        .service(put_layout_params)
+        .service(from_channel)
+        .service(websocket)
+        .service(stats);
 }
 
 // Function to configure and run the Actix-web server
 pub async fn run_server(
     listen_addr: SocketAddr,
-    data: GraphDataType,
-    bg_control: bg_layout::BgControl,
+    graphs: GraphsType,
     addresses: tokio::sync::oneshot::Sender<Vec<std::net::SocketAddr>>,
 ) -> Result<actix_web::dev::Server, Error> {
     let server = Arc::new(
         HttpServer::new(move || {
             App::new()
                 .wrap(Logger::default())
-                .app_data(web::Data::new(data.clone()))
-                .app_data(web::Data::new(bg_control.clone()))
-                .service(list)
-                .service(add)
-                .service(post_graphviz)
-                .service(from_channel)
+                .app_data(web::Data::new(graphs.clone()))
+                .service(list_graphs)
+                .service(web::scope("/g/{name}").configure(graph_services))
+                .configure(graph_services)
                 .service(assets::assets("", "index.html"))
         })
         .bind(listen_addr)?,
@@ -0,0 +1,170 @@
+//! Saving all the graphs to a state file, and loading them back when starting, so that the
+//! graphs and their layouts survive restarts.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::graph::{Graph, GraphSnapshot};
+use crate::graphs::Graphs;
+use crate::layout::{EngineKind, LayoutParams};
+
+/// The version of the state file format. Files of other versions aren't loaded.
+const STATE_VERSION: u32 = 1;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to access state file {path}: {source}")]
+    IOError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid state file {path}: {source}")]
+    JsonError {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error(
+        "State file {path} has version {version}, but only version {STATE_VERSION} is supported"
+    )]
+    VersionError { path: PathBuf, version: u32 },
+
+    #[error("Graph error: {source}")]
+    GraphError {
+        #[from]
+        source: crate::graph::Error,
+    },
+
+    #[error("Layout error: {source}")]
+    LayoutError {
+        #[from]
+        source: crate::layout::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    version: u32,
+    graphs: BTreeMap<String, GraphState>,
+}
+
+// The version is checked before the rest of the file is parsed
+#[derive(Deserialize)]
+struct StateVersion {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GraphState {
+    graph: GraphSnapshot,
+    engine: Option<EngineKind>,
+    seed: Option<u64>,
+    params: LayoutParams,
+}
+
+/// Loads the graphs from the state file, keeping their saved positions as they are. A missing
+/// file is fine, as it's created on the first save.
+pub async fn load(path: &Path, graphs: &Graphs) -> Result<()> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(source) => {
+            return Err(Error::IOError {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+    let json_error = |source| Error::JsonError {
+        path: path.to_path_buf(),
+        source,
+    };
+    let StateVersion { version } = serde_json::from_slice(&contents).map_err(json_error)?;
+    if version != STATE_VERSION {
+        return Err(Error::VersionError {
+            path: path.to_path_buf(),
+            version,
+        });
+    }
+    let state: State = serde_json::from_slice(&contents).map_err(json_error)?;
+
+    for (name, graph_state) in state.graphs {
+        // The params are checked like when they're set, so that the layout finishes
+        graph_state.params.validate()?;
+        let graph = Graph::from_snapshot(graph_state.graph)?;
+        let entry = graphs.get_or_create(&name).await;
+        let mut data = entry.data.lock().await;
+        data.graph = graph;
+        data.engine = graph_state.engine;
+        data.seed = graph_state.seed;
+        data.params = graph_state.params;
+        data.restore_layout();
+    }
+    Ok(())
+}
+
+/// Saves all the graphs to the state file. The file is replaced only once the new one has
+/// been written, so that a crash while saving doesn't lose the old state.
+pub async fn save(path: &Path, graphs: &Graphs) -> Result<()> {
+    let contents = serialize(graphs).await?;
+    write(path, &contents).await
+}
+
+/// Saves the graphs every interval, when they have changed since the last save
+pub async fn save_periodically(path: PathBuf, graphs: &Graphs, interval: Duration) {
+    let mut saved = None;
+    loop {
+        tokio::time::sleep(interval).await;
+        let result = match serialize(graphs).await {
+            Ok(contents) if saved.as_ref() == Some(&contents) => Ok(()),
+            Ok(contents) => write(&path, &contents)
+                .await
+                .map(|()| saved = Some(contents)),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            log::error!("{err}");
+        }
+    }
+}
+
+async fn serialize(graphs: &Graphs) -> Result<Vec<u8>> {
+    let mut state = State {
+        version: STATE_VERSION,
+        graphs: BTreeMap::new(),
+    };
+    for (name, entry) in graphs.entries().await {
+        let data = entry.data.lock().await;
+        state.graphs.insert(
+            name,
+            GraphState {
+                graph: data.graph.snapshot()?,
+                engine: data.engine,
+                seed: data.seed,
+                params: data.params.clone(),
+            },
+        );
+    }
+    // Serializing doesn't fail, as the keys of the maps are strings
+    Ok(serde_json::to_vec(&state).expect("Expected state to serialize"))
+}
+
+async fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let io_error = |source| Error::IOError {
+        path: path.to_path_buf(),
+        source,
+    };
+    tokio::fs::write(&temp_path, contents)
+        .await
+        .map_err(io_error)?;
+    tokio::fs::rename(&temp_path, path).await.map_err(io_error)
+}
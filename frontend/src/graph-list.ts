@@ -0,0 +1,52 @@
+import { select } from "d3-selection"; // For d3.select
+
+interface GraphInfo {
+    name: string;
+    nodes: number;
+    edges: number;
+}
+
+const GRAPHS_ENDPOINT: string = "/graphs";
+const REFRESH_INTERVAL_MS: number = 2000;
+
+/**
+ * Fetches the list of graphs and renders it as links to the graph views.
+ */
+async function fetchAndRenderList(): Promise<void> {
+    try {
+        const response: Response = await fetch(GRAPHS_ENDPOINT);
+        if (!response.ok) {
+            throw new Error(`HTTP error! status: ${response.status}`);
+        }
+        const graphs: GraphInfo[] = await response.json();
+
+        const items = select("#graph-list")
+            .selectAll<HTMLLIElement, GraphInfo>("li")
+            .data(graphs, (d) => d.name);
+
+        // Exit
+        items.exit().remove();
+
+        // Enter
+        const newItems = items.enter().append("li");
+        newItems
+            .append("a")
+            .attr(
+                "href",
+                (d: GraphInfo) => `index.html?graph=${encodeURIComponent(d.name)}`
+            )
+            .text((d: GraphInfo) => d.name);
+        newItems.append("span");
+
+        // Update + Enter
+        newItems
+            .merge(items)
+            .select("span")
+            .text((d: GraphInfo) => ` (${d.nodes} nodes, ${d.edges} edges)`);
+    } catch (error: any) {
+        console.error("Error fetching graph list:", error);
+    }
+}
+
+fetchAndRenderList();
+setInterval(fetchAndRenderList, REFRESH_INTERVAL_MS);
@@ -1,16 +1,21 @@
-import { select } from "d3-selection"; // For d3.select
+import { pointer, select } from "d3-selection"; // For d3.select and d3.pointer
 import { ScaleLinear, scaleLinear } from "d3-scale"; // For d3.scaleLinear
 import { min, max } from "d3-array"; // For d3.min, d3.max
 
+// Graphviz attributes, e.g. color or shape
+type Attributes = { [name: string]: string };
+
 // Define interfaces for graph data
 interface NodeData {
     id: string;
     data: {
         label: string;
+        attrs: Attributes;
         // Add other properties if they exist in your node data
     };
     // Add other properties if they exist in your node structure
     pos: [number, number]; // [x, y] coordinates
+    pinned: boolean; // Pinned nodes are not moved by the layout
 }
 
 interface EdgeData {
@@ -18,29 +23,90 @@ interface EdgeData {
     1: string; // Target Node ID
     2: {
         id: string; // Edge ID
-        // Add other properties if they exist in your edge data
+        attrs: Attributes;
+        // Control points of cubic Bézier curves from the source to the target, or empty for
+        // a straight line
+        route: [number, number][];
     };
 }
 
+interface GroupData {
+    id: string;
+    label: string | null;
+    parent: string | null;
+    nodes: string[]; // Only the nodes directly in this group
+    cluster: boolean;
+    attrs: Attributes;
+}
+
 interface GraphData {
     nodes: NodeData[];
     edges: EdgeData[];
+    groups: GroupData[];
     creation_time: number;
+    directed: boolean;
+    attrs: Attributes;
 }
 
-interface SSEData {
-    graph: GraphData;
+// The changes to the graph since the previous update
+interface GraphDelta {
+    added_nodes: NodeData[]; // Replace existing nodes with the same id
+    removed_nodes: string[];
+    moved_nodes: [string, [number, number]][];
+    added_edges: EdgeData[]; // Replace existing edges with the same id
+    removed_edges: string[];
+    // Only present when changed
+    directed: boolean | null;
+    attrs: Attributes | null;
+    groups: GroupData[] | null;
 }
 
-const GRAPH_ENDPOINT: string = "/graph";
+// A delta applies on top of any state between its base serial and its serial
+type UpdateData =
+    | { type: "snapshot"; serial: number; graph: GraphData }
+    | {
+          type: "delta";
+          serial: number;
+          base_serial: number;
+          delta: GraphDelta;
+      };
+
+// The WebSocket carries the updates, and the errors from the commands sent by us
+type ServerMessage = UpdateData | { type: "error"; message: string };
+
+// The edits sent to the backend. Their results come back as updates like any other change.
+type ClientCommand =
+    | { type: "move_node"; id: string; pos: [number, number] }
+    | { type: "pin_node"; id: string }
+    | { type: "unpin_node"; id: string }
+    | { type: "delete_node"; id: string }
+    | {
+          type: "add_edge";
+          a: string;
+          b: string;
+          id: string | null;
+          attrs: Attributes;
+      };
+
+// Named graphs are viewed with ?graph=name, otherwise the default graph is shown
+const GRAPH_NAME: string | null = new URLSearchParams(window.location.search).get(
+    "graph"
+);
+const GRAPH_BASE: string =
+    GRAPH_NAME === null ? "" : `/g/${encodeURIComponent(GRAPH_NAME)}`;
+
 const RETRY_INTERVAL_MS: number = 2000;
-const STREAM_ENDPOINT: string = "/stream";
+const WS_ENDPOINT: string = `${
+    window.location.protocol === "https:" ? "wss:" : "ws:"
+}//${window.location.host}${GRAPH_BASE}/ws`;
+
+const CLUSTER_PADDING: number = 10;
 
 const margin = { top: 20, right: 20, bottom: 20, left: 20 };
 const width: number = 800 - margin.left - margin.right; // SVG width
 const height: number = 600 - margin.top - margin.bottom; // SVG height
 
-let eventSource: EventSource | null = null;
+let socket: WebSocket | null = null;
 
 // Create the SVG container
 const svg = select("#graph-container")
@@ -54,7 +120,7 @@ svg.append("defs")
     .append("marker")
     .attr("id", "arrowhead")
     .attr("viewBox", "0 -5 10 10") // Adjust viewBox based on your arrow size
-    .attr("refX", 8) // This positions the tip of the arrow at the end of the line
+    .attr("refX", 8) // This positions the tip of the arrow at the end of the link
     .attr("refY", 0)
     .attr("orient", "auto")
     .attr("markerWidth", 10) // Size of the marker
@@ -67,13 +133,166 @@ svg.append("defs")
 let xScale: ScaleLinear<number, number> = scaleLinear();
 let yScale: ScaleLinear<number, number> = scaleLinear();
 
-// Elements for nodes and links
+// Elements for clusters, nodes and links
+let clusterGroup = svg.append("g").attr("class", "clusters");
 let nodeGroup = svg.append("g").attr("class", "nodes");
 let linkGroup = svg.append("g").attr("class", "links");
 let nodeLabelGroup = svg.append("g").attr("class", "nodeLabels");
 
 let lastCreationTime: number | null = null;
 
+// The graph as currently shown, and the serial of the update it's based on, if known
+let currentGraph: GraphData | null = null;
+let currentSerial: number | null = null;
+
+// The node being dragged, and its position so far. The position is sent when dropped.
+let drag: { id: string; pos: [number, number]; moved: boolean } | null = null;
+
+/**
+ * Applies the changes from a delta update to a graph.
+ * @param graph The graph the delta is based on.
+ * @param delta The changes to apply.
+ * @returns The updated graph.
+ */
+function applyDelta(graph: GraphData, delta: GraphDelta): GraphData {
+    const nodes = new Map<string, NodeData>(
+        graph.nodes.map((node) => [node.id, node])
+    );
+    for (const id of delta.removed_nodes) {
+        nodes.delete(id);
+    }
+    for (const node of delta.added_nodes) {
+        nodes.set(node.id, node);
+    }
+    for (const [id, pos] of delta.moved_nodes) {
+        const node = nodes.get(id);
+        if (node !== undefined) {
+            nodes.set(id, { ...node, pos });
+        }
+    }
+
+    const edges = new Map<string, EdgeData>(
+        graph.edges.map((edge) => [edge[2].id, edge])
+    );
+    for (const id of delta.removed_edges) {
+        edges.delete(id);
+    }
+    for (const edge of delta.added_edges) {
+        edges.set(edge[2].id, edge);
+    }
+
+    return {
+        ...graph,
+        nodes: [...nodes.values()],
+        // Only keep the edges whose both ends are known, in case some updates were missed
+        edges: [...edges.values()].filter(
+            (edge) => nodes.has(edge[0]) && nodes.has(edge[1])
+        ),
+        directed: delta.directed ?? graph.directed,
+        attrs: delta.attrs ?? graph.attrs,
+        groups: delta.groups ?? graph.groups,
+    };
+}
+
+interface ClusterBox {
+    group: GroupData;
+    x: number;
+    y: number;
+    width: number;
+    height: number;
+}
+
+/**
+ * Computes the bounding boxes of the clusters in SVG coordinates. Clusters also contain the
+ * nodes of the groups nested in them.
+ * @param graphData The graph with the groups.
+ * @param nodesById The nodes of the graph.
+ */
+function clusterBoxes(
+    graphData: GraphData,
+    nodesById: Map<string, NodeData>
+): ClusterBox[] {
+    const children = new Map<string, GroupData[]>();
+    for (const group of graphData.groups) {
+        if (group.parent !== null) {
+            children.set(group.parent, [
+                ...(children.get(group.parent) ?? []),
+                group,
+            ]);
+        }
+    }
+    const memberIds = (group: GroupData): string[] =>
+        group.nodes.concat(...(children.get(group.id) ?? []).map(memberIds));
+    // Outer clusters get more padding, so that nested clusters stay visible
+    const nestingDepth = (group: GroupData): number =>
+        1 + (max((children.get(group.id) ?? []).map(nestingDepth)) ?? 0);
+
+    const boxes: ClusterBox[] = [];
+    for (const group of graphData.groups.filter((group) => group.cluster)) {
+        const members = memberIds(group)
+            .map((id) => nodesById.get(id))
+            .filter((node): node is NodeData => node !== undefined);
+        if (members.length === 0) {
+            continue;
+        }
+        const xs: number[] = members.map((node) => xScale(node.pos[0]));
+        const ys: number[] = members.map((node) => yScale(node.pos[1]));
+        const padding: number = CLUSTER_PADDING * nestingDepth(group);
+        const x0: number = min(xs)! - padding;
+        const y0: number = min(ys)! - padding;
+        boxes.push({
+            group,
+            x: x0,
+            y: y0,
+            width: max(xs)! + padding - x0,
+            height: max(ys)! + padding - y0,
+        });
+    }
+    return boxes;
+}
+
+/**
+ * Maps the Graphviz style attribute to a stroke-dasharray.
+ * @param attrs The attributes of the node or the edge.
+ */
+function dashArray(attrs: Attributes): string | null {
+    const style = attrs.style ?? "";
+    if (style.includes("dashed")) {
+        return "5,3";
+    } else if (style.includes("dotted")) {
+        return "1,3";
+    } else {
+        return null;
+    }
+}
+
+/**
+ * The SVG path of an edge, following its route. The route is only used while its ends are
+ * at the nodes, as it's out of date e.g. while a node is being dragged.
+ * @param edge The edge to draw.
+ * @param nodesById The nodes of the graph by their ids.
+ */
+function linkPath(edge: EdgeData, nodesById: Map<string, NodeData>): string {
+    const source = nodesById.get(edge[0])!.pos;
+    const target = nodesById.get(edge[1])!.pos;
+    const point = (pos: [number, number]) => `${xScale(pos[0])},${yScale(pos[1])}`;
+    const route = edge[2].route ?? [];
+    const isAt = (pos: [number, number], nodePos: [number, number]) =>
+        Math.abs(pos[0] - nodePos[0]) < 0.5 && Math.abs(pos[1] - nodePos[1]) < 0.5;
+    if (
+        route.length >= 4 &&
+        isAt(route[0], source) &&
+        isAt(route[route.length - 1], target)
+    ) {
+        let path = `M${point(route[0])}`;
+        for (let i = 1; i + 2 < route.length; i += 3) {
+            path += `C${point(route[i])} ${point(route[i + 1])} ${point(route[i + 2])}`;
+        }
+        return path;
+    }
+    return `M${point(source)}L${point(target)}`;
+}
+
 /**
  * Updates the D3 graph visualization based on the provided graph data.
  * @param graphData The data containing nodes and edges to render.
@@ -84,6 +303,17 @@ function updateGraph(graphData: GraphData): void {
         return;
     }
 
+    // The dragged node follows the pointer instead of the updates
+    const dragged = drag;
+    if (dragged !== null) {
+        graphData = {
+            ...graphData,
+            nodes: graphData.nodes.map((node) =>
+                node.id === dragged.id ? { ...node, pos: dragged.pos } : node
+            ),
+        };
+    }
+
     // Create a map for quick node lookup by ID
     const nodesById = new Map<string, NodeData>(
         graphData.nodes.map((d) => [d.id, d])
@@ -105,9 +335,42 @@ function updateGraph(graphData: GraphData): void {
     xScale.domain([xMin - xPadding, xMax + xPadding]).range([0, width]);
     yScale.domain([yMin - yPadding, yMax + yPadding]).range([height, 0]); // Invert Y for SVG coordinates
 
+    // --- Update Clusters ---
+    const clusters = clusterGroup
+        .selectAll<SVGGElement, ClusterBox>(".cluster") // Explicitly type the selection
+        .data(clusterBoxes(graphData, nodesById), (d) => d.group.id);
+
+    // Exit
+    clusters.exit().remove();
+
+    // Enter
+    const newClusters = clusters.enter().append("g").attr("class", "cluster");
+    newClusters.append("rect");
+    newClusters.append("text");
+
+    // Update + Enter
+    const allClusters = newClusters.merge(clusters);
+    allClusters
+        .select("rect")
+        .attr("x", (d: ClusterBox) => d.x)
+        .attr("y", (d: ClusterBox) => d.y)
+        .attr("width", (d: ClusterBox) => d.width)
+        .attr("height", (d: ClusterBox) => d.height)
+        .style("stroke", (d: ClusterBox) => d.group.attrs.color ?? null)
+        .style(
+            "fill",
+            (d: ClusterBox) =>
+                d.group.attrs.fillcolor ?? d.group.attrs.bgcolor ?? null
+        );
+    allClusters
+        .select("text")
+        .attr("x", (d: ClusterBox) => d.x + 4)
+        .attr("y", (d: ClusterBox) => d.y + 8)
+        .text((d: ClusterBox) => d.group.label ?? "");
+
     // --- Update Links ---
     const links = linkGroup
-        .selectAll<SVGLineElement, EdgeData>(".link") // Explicitly type the selection
+        .selectAll<SVGPathElement, EdgeData>(".link") // Explicitly type the selection
         // The key function now uses the 'id' from the third element of the edge array
         .data(graphData.edges, (d) => `edge-${d[2].id}`);
 
@@ -115,16 +378,16 @@ function updateGraph(graphData: GraphData): void {
     links.exit().remove();
 
     // Enter
-    const newLinks = links.enter().append("line").attr("class", "link");
+    const newLinks = links.enter().append("path").attr("class", "link");
 
     // Update + Enter
     const allLinks = newLinks
         .merge(links)
-        .attr("x1", (d: EdgeData) => xScale(nodesById.get(d[0])!.pos[0])) // d[0] is source_id
-        .attr("y1", (d: EdgeData) => yScale(nodesById.get(d[0])!.pos[1]))
-        .attr("x2", (d: EdgeData) => xScale(nodesById.get(d[1])!.pos[0])) // d[1] is target_id
-        .attr("y2", (d: EdgeData) => yScale(nodesById.get(d[1])!.pos[1]))
-        .attr("marker-end", "url(#arrowhead)");
+        .attr("d", (d: EdgeData) => linkPath(d, nodesById))
+        .attr("marker-end", graphData.directed ? "url(#arrowhead)" : null)
+        .style("stroke", (d: EdgeData) => d[2].attrs.color ?? null)
+        .style("stroke-width", (d: EdgeData) => d[2].attrs.penwidth ?? null)
+        .style("stroke-dasharray", (d: EdgeData) => dashArray(d[2].attrs));
 
     // --- Update Nodes ---
     const nodes = nodeGroup
@@ -135,19 +398,44 @@ function updateGraph(graphData: GraphData): void {
     nodes.exit().remove();
 
     // Enter
-    const newNodeGroup = nodes.enter().append("g").attr("class", "node");
+    const newNodeGroup = nodes
+        .enter()
+        .append("g")
+        .attr("class", "node")
+        .on("pointerdown", (event: PointerEvent, d: NodeData) => {
+            drag = { id: d.id, pos: d.pos, moved: false };
+        })
+        .on("dblclick", (event: MouseEvent, d: NodeData) => {
+            sendCommand({ type: d.pinned ? "unpin_node" : "pin_node", id: d.id });
+        });
 
     newNodeGroup.append("circle").attr("r", 5); // Default radius
+    newNodeGroup.append("title");
 
     // Update + Enter (position nodes)
     const allNodes = newNodeGroup
         .merge(nodes)
+        .classed("pinned", (d: NodeData) => d.pinned)
         .attr(
             "transform",
             (d: NodeData) =>
                 `translate(${xScale(d.pos[0])},${yScale(d.pos[1])})`
         );
 
+    // Update styling (in case attributes change)
+    allNodes
+        .select("circle")
+        .style(
+            "fill",
+            (d: NodeData) => d.data.attrs.fillcolor ?? d.data.attrs.color ?? null
+        )
+        .style("stroke", (d: NodeData) => d.data.attrs.color ?? null)
+        .style("stroke-width", (d: NodeData) => d.data.attrs.penwidth ?? null)
+        .style("stroke-dasharray", (d: NodeData) => dashArray(d.data.attrs));
+    allNodes
+        .select("title")
+        .text((d: NodeData) => d.data.attrs.tooltip ?? d.data.label);
+
     // --- Update Node labels ---
     const nodeLabels = nodeLabelGroup
         .selectAll<SVGGElement, NodeData>(".nodeLabel") // Explicitly type the selection
@@ -177,56 +465,116 @@ function updateGraph(graphData: GraphData): void {
         );
 
     // Update text (in case labels change)
-    allNodeLabels.select("text").text((d: NodeData) => d.data.label);
+    allNodeLabels
+        .select("text")
+        .text((d: NodeData) => d.data.label)
+        .style("fill", (d: NodeData) => d.data.attrs.fontcolor ?? null);
 }
 
 /**
- * Fetches graph data from the endpoint and renders it.
+ * Sends an edit to the backend, if connected.
+ * @param command The edit to send.
  */
-async function fetchDataAndRender(): Promise<boolean> {
-    try {
-        const response: Response = await fetch(GRAPH_ENDPOINT);
-        if (!response.ok) {
-            throw new Error(`HTTP error! status: ${response.status}`);
-        }
-        const data: GraphData = await response.json();
+function sendCommand(command: ClientCommand): void {
+    if (socket?.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify(command));
+    }
+}
+
+// Dragging continues outside the node, so the pointer is followed on the whole window
+window.addEventListener("pointermove", (event: PointerEvent) => {
+    if (drag === null || currentGraph === null) {
+        return;
+    }
+    const [x, y] = pointer(event, svg.node());
+    drag.pos = [xScale.invert(x), yScale.invert(y)];
+    drag.moved = true;
+    updateGraph(currentGraph);
+});
+
+window.addEventListener("pointerup", () => {
+    const dropped = drag;
+    drag = null;
+    if (dropped === null || !dropped.moved) {
+        return;
+    }
+    // Dropped nodes stay where they were put until unpinned with a double click
+    sendCommand({ type: "move_node", id: dropped.id, pos: dropped.pos });
+    sendCommand({ type: "pin_node", id: dropped.id });
+    if (currentGraph !== null) {
+        currentGraph = applyDelta(currentGraph, {
+            added_nodes: [],
+            removed_nodes: [],
+            moved_nodes: [[dropped.id, dropped.pos]],
+            added_edges: [],
+            removed_edges: [],
+            directed: null,
+            attrs: null,
+            groups: null,
+        });
+        updateGraph(currentGraph);
+    }
+});
+
+/**
+ * Applies an update from the backend to the shown graph.
+ * @param data The update.
+ */
+function handleUpdate(data: UpdateData): void {
+    if (data.type === "snapshot") {
         if (lastCreationTime === null) {
-            lastCreationTime = data.creation_time;
-        } else if (data.creation_time !== lastCreationTime) {
+            lastCreationTime = data.graph.creation_time;
+        } else if (data.graph.creation_time !== lastCreationTime) {
             window.location.reload();
-            return true;
+            return;
         }
-        updateGraph(data);
-        return true;
-    } catch (error: any) {
-        // Use 'any' or more specific error types if known
-        console.error("Error fetching graph data:", error);
-        return false;
+        currentGraph = data.graph;
+    } else if (currentGraph === null) {
+        return;
+    } else if (currentSerial !== null && data.serial <= currentSerial) {
+        return; // Already included in the current graph
+    } else if (currentSerial !== null && data.base_serial > currentSerial) {
+        // Some updates were missed, so start over from a new snapshot
+        reconnect();
+        return;
+    } else {
+        currentGraph = applyDelta(currentGraph, data.delta);
     }
+    currentSerial = data.serial;
+    updateGraph(currentGraph);
 }
 
-async function subscribeToStream(): Promise<void> {
-    eventSource = new EventSource(STREAM_ENDPOINT);
-    eventSource.onmessage = (event: MessageEvent) => {
-        const data: SSEData = JSON.parse(event.data);
-        updateGraph(data.graph);
+/**
+ * Connects to the backend. The first update is always a snapshot of the whole graph, and the
+ * connection is retried if it breaks, e.g. when the graph doesn't exist yet.
+ */
+function connect(): void {
+    const ws = new WebSocket(WS_ENDPOINT);
+    socket = ws;
+    ws.onmessage = (event: MessageEvent) => {
+        const message: ServerMessage = JSON.parse(event.data);
+        if (message.type === "error") {
+            console.warn("Graph edit failed:", message.message);
+        } else {
+            handleUpdate(message);
+        }
     };
-
-    eventSource.onerror = (event: Event) => {
-        setup();
+    ws.onclose = () => {
+        if (socket === ws) {
+            console.log(
+                `Disconnected from backend, trying again after ${RETRY_INTERVAL_MS} ms`
+            );
+            setTimeout(connect, RETRY_INTERVAL_MS);
+        }
     };
 }
 
-async function setup() {
-    // Initial fetch and then poll
-    if (await fetchDataAndRender()) {
-        // Subscribe to further updates
-        subscribeToStream();
-    } else {
-        console.log(
-            `Failed to connect to backend, trying again after ${RETRY_INTERVAL_MS} ms`
-        );
-        setTimeout(setup, RETRY_INTERVAL_MS);
+function reconnect(): void {
+    const ws = socket;
+    if (ws !== null) {
+        ws.onmessage = null;
     }
+    connect();
+    ws?.close();
 }
-setup();
+connect();